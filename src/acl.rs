@@ -0,0 +1,481 @@
+// --acl: per-path permissions. The file is INI-like:
+//
+//   [groups]
+//   staff = alice, bob
+//
+//   [/]
+//   * = read, list
+//
+//   [/uploads]
+//   * = read, list
+//   @authenticated = read, list, upload
+//
+//   [/private]
+//   @staff = read, list
+//   alice = admin
+//
+//   [/**/*.key]
+//   alice = read
+//
+// Section headers are a path below the root (matching it and everything
+// under it) or a glob matched against the whole path. The last section
+// matching a path decides, so general sections go first; within it, the
+// permissions of every line naming the user are combined. `*` is anyone
+// including anonymous clients, `@authenticated` anyone who logged in, `@name`
+// a group. Paths no section matches are not accessible. Permissions: read (download files), list
+// (directory listings, search, archives), upload (console uploads into the
+// directory), admin (console commands; implies the others).
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    ops::BitOr,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use globset::{GlobBuilder, GlobMatcher};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Perms(u8);
+
+impl Perms {
+    pub const NONE: Perms = Perms(0);
+    pub const READ: Perms = Perms(1);
+    pub const LIST: Perms = Perms(2);
+    pub const UPLOAD: Perms = Perms(4);
+    pub const ADMIN: Perms = Perms(8);
+    pub const ALL: Perms = Perms(15);
+
+    pub fn contains(self, other: Perms) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: Perms) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        let mut perms = Perms::NONE;
+        for p in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            perms = perms
+                | match p {
+                    "read" => Perms::READ,
+                    "list" => Perms::LIST,
+                    "upload" => Perms::UPLOAD,
+                    "admin" => Perms::ALL,
+                    "none" => Perms::NONE,
+                    other => return Err(format!("unknown permission {other:?}")),
+                };
+        }
+        Ok(perms)
+    }
+}
+
+impl BitOr for Perms {
+    type Output = Perms;
+
+    fn bitor(self, rhs: Perms) -> Perms {
+        Perms(self.0 | rhs.0)
+    }
+}
+
+enum Pattern {
+    Prefix(String), // "/" or "/a/b", no trailing '/'
+    Glob(GlobMatcher),
+}
+
+impl Pattern {
+    fn parse(s: &str) -> Result<Self, String> {
+        if !s.starts_with('/') {
+            return Err(format!("section {s:?} must start with '/'"));
+        }
+        if s.contains(['*', '?', '[', '{']) {
+            return GlobBuilder::new(s)
+                .literal_separator(true)
+                .build()
+                .map(|g| Pattern::Glob(g.compile_matcher()))
+                .map_err(|e| format!("section {s:?}: {}", e.kind()));
+        }
+        let trimmed = s.trim_end_matches('/');
+        Ok(Pattern::Prefix(if trimmed.is_empty() {
+            "/".to_string()
+        } else {
+            trimmed.to_string()
+        }))
+    }
+
+    fn matches(&self, rel: &str) -> bool {
+        match self {
+            Pattern::Prefix(p) if p == "/" => true,
+            Pattern::Prefix(p) => rel
+                .strip_prefix(p.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+            Pattern::Glob(g) => g.is_match(rel),
+        }
+    }
+}
+
+enum Who {
+    Anyone,
+    Authenticated,
+    Group(String),
+    User(String),
+}
+
+struct Section {
+    pattern: Pattern,
+    grants: Vec<(Who, Perms)>,
+}
+
+pub struct Acl {
+    root: PathBuf, // canonicalized served root
+    groups: HashMap<String, HashSet<String>>,
+    sections: Vec<Section>,
+}
+
+impl Acl {
+    pub fn load(path: &Path, root: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read --acl {}: {e}", path.display()))?;
+        parse(&text, root).map_err(|(line, e)| format!("{}:{line}: {e}", path.display()))
+    }
+
+    pub fn section_count(&self) -> usize {
+        self.sections.len()
+    }
+
+    // `rel` is '/'-separated below the root, starting with '/'.
+    fn perms(&self, rel: &str, user: Option<&str>) -> Perms {
+        let Some(section) = self.sections.iter().rev().find(|s| s.pattern.matches(rel)) else {
+            return Perms::NONE;
+        };
+        section
+            .grants
+            .iter()
+            .filter(|(who, _)| self.names(who, user))
+            .fold(Perms::NONE, |acc, (_, p)| acc | *p)
+    }
+
+    fn names(&self, who: &Who, user: Option<&str>) -> bool {
+        match (who, user) {
+            (Who::Anyone, _) => true,
+            (Who::Authenticated, Some(_)) => true,
+            (Who::Group(g), Some(u)) => self.groups.get(g).is_some_and(|m| m.contains(u)),
+            (Who::User(name), Some(u)) => name == u,
+            _ => false,
+        }
+    }
+}
+
+fn parse(text: &str, root: &Path) -> Result<Acl, (usize, String)> {
+    let mut groups: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut sections: Vec<Section> = Vec::new();
+    let mut in_groups = false;
+    let mut group_refs = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let n = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let header = header.trim();
+            in_groups = header == "groups";
+            if !in_groups {
+                let pattern = Pattern::parse(header).map_err(|e| (n, e))?;
+                sections.push(Section {
+                    pattern,
+                    grants: Vec::new(),
+                });
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err((n, "expected name = value".to_string()));
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if key.is_empty() {
+            return Err((n, "missing name before '='".to_string()));
+        }
+
+        if in_groups {
+            let members = value
+                .split(',')
+                .map(str::trim)
+                .filter(|m| !m.is_empty())
+                .map(str::to_string);
+            groups.entry(key.to_string()).or_default().extend(members);
+            continue;
+        }
+        let Some(section) = sections.last_mut() else {
+            return Err((n, "rule outside of a [/path] section".to_string()));
+        };
+        let who = match key {
+            "*" => Who::Anyone,
+            "@authenticated" => Who::Authenticated,
+            _ => match key.strip_prefix('@') {
+                Some(g) => {
+                    group_refs.push((n, g.to_string()));
+                    Who::Group(g.to_string())
+                }
+                None => Who::User(key.to_string()),
+            },
+        };
+        let perms = Perms::parse(value).map_err(|e| (n, e))?;
+        section.grants.push((who, perms));
+    }
+
+    // Groups may be defined after they're used, so check references last.
+    if let Some((n, g)) = group_refs.iter().find(|(_, g)| !groups.contains_key(g)) {
+        return Err((*n, format!("unknown group @{g}")));
+    }
+    Ok(Acl {
+        root: root.to_path_buf(),
+        groups,
+        sections,
+    })
+}
+
+// What one request may do: the ACL, if any, and who is asking. Without an
+// ACL, `fallback` applies everywhere, or only below `scope` when set.
+#[derive(Clone)]
+pub struct Access {
+    acl: Option<Arc<Acl>>,
+    user: Option<String>,
+    fallback: Perms,
+    scope: Option<PathBuf>,
+}
+
+impl Access {
+    pub fn new(acl: Option<Arc<Acl>>, user: Option<String>, fallback: Perms) -> Self {
+        Self {
+            acl,
+            user,
+            fallback,
+            scope: None,
+        }
+    }
+
+    // A share link: `perms` on `scope` (on disk) and below, nothing else,
+    // whatever the ACL says.
+    pub fn share(scope: PathBuf, perms: Perms) -> Self {
+        Self {
+            acl: None,
+            user: None,
+            fallback: perms,
+            scope: Some(scope),
+        }
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    // `path` is on disk; anything outside the root gets nothing.
+    pub fn perms(&self, path: &Path) -> Perms {
+        if let Some(scope) = &self.scope {
+            return if path.starts_with(scope) {
+                self.fallback
+            } else {
+                Perms::NONE
+            };
+        }
+        let Some(acl) = &self.acl else {
+            return self.fallback;
+        };
+        let Ok(rel) = path.strip_prefix(&acl.root) else {
+            return Perms::NONE;
+        };
+        acl.perms(&acl_path(rel), self.user())
+    }
+
+    pub fn allows(&self, path: &Path, need: Perms) -> bool {
+        self.perms(path).contains(need)
+    }
+
+    // Whether an entry of `dir` shows up in listings, searches, manifests
+    // and archives: directories need list, files read.
+    pub fn can_see(&self, dir: &Path, name: &OsStr, is_dir: bool) -> bool {
+        if self.acl.is_none() && self.scope.is_none() {
+            return self.fallback.contains(Perms::LIST | Perms::READ);
+        }
+        let need = if is_dir { Perms::LIST } else { Perms::READ };
+        self.allows(&dir.join(name), need)
+    }
+
+    // Whether any path grants one of `perms`; decides who gets the console.
+    pub fn anywhere(&self, perms: Perms) -> bool {
+        let Some(acl) = &self.acl else {
+            return self.fallback.intersects(perms);
+        };
+        acl.sections.iter().any(|s| {
+            s.grants
+                .iter()
+                .any(|(who, p)| p.intersects(perms) && acl.names(who, self.user()))
+        })
+    }
+}
+
+fn acl_path(rel: &Path) -> String {
+    let mut out = String::new();
+    for c in rel.components() {
+        match c {
+            Component::Normal(s) => {
+                out.push('/');
+                out.push_str(&s.to_string_lossy());
+            }
+            Component::ParentDir => out.push_str("/.."),
+            _ => {}
+        }
+    }
+    if out.is_empty() {
+        out.push('/');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = "
+        [groups]
+        staff = alice, bob
+
+        [/]
+        * = read, list
+
+        [/uploads]
+        @authenticated = read, list, upload
+
+        [/private]
+        @staff = read, list
+        alice = admin
+
+        [/**/*.key]
+        alice = read
+
+        [/docs/draft*]
+        carol = read
+    ";
+
+    fn access(user: Option<&str>) -> Access {
+        let acl = parse(RULES, Path::new("/srv")).unwrap();
+        Access::new(Some(Arc::new(acl)), user.map(str::to_string), Perms::NONE)
+    }
+
+    fn perms(user: Option<&str>, path: &str) -> Perms {
+        access(user).perms(&Path::new("/srv").join(path.trim_start_matches('/')))
+    }
+
+    #[test]
+    fn last_matching_section_wins() {
+        let rl = Perms::READ | Perms::LIST;
+        let cases = [
+            (None, "/", rl),
+            (None, "/pub/a.txt", rl),
+            (None, "/uploads", Perms::NONE),
+            (Some("dave"), "/uploads/x", rl | Perms::UPLOAD),
+            (None, "/private/s.txt", Perms::NONE),
+            (Some("dave"), "/private/s.txt", Perms::NONE),
+            (Some("bob"), "/private/s.txt", rl),
+            (Some("alice"), "/private", Perms::ALL),
+            // the glob section comes last, so it replaces the others
+            (Some("alice"), "/private/id.key", Perms::READ),
+            (Some("bob"), "/private/id.key", Perms::NONE),
+            (None, "/pub/x.key", Perms::NONE),
+        ];
+        for (user, path, want) in cases {
+            assert_eq!(perms(user, path), want, "{user:?} {path}");
+        }
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        let rl = Perms::READ | Perms::LIST;
+        assert_eq!(perms(Some("alice"), "/private"), Perms::ALL);
+        assert_eq!(perms(Some("alice"), "/private/"), Perms::ALL);
+        assert_eq!(perms(Some("alice"), "/private/a/b"), Perms::ALL);
+        assert_eq!(perms(Some("alice"), "/privateer"), rl);
+        assert_eq!(perms(Some("alice"), "/private-not/x"), rl);
+    }
+
+    #[test]
+    fn globs_match_the_whole_path() {
+        assert_eq!(perms(Some("carol"), "/docs/draft1.md"), Perms::READ);
+        assert_eq!(perms(Some("carol"), "/docs/draft"), Perms::READ);
+        // `*` doesn't cross '/', and the glob isn't a prefix: "[/]" applies
+        assert_eq!(
+            perms(Some("carol"), "/docs/draft1/x.md"),
+            Perms::READ | Perms::LIST
+        );
+        assert_eq!(
+            perms(Some("carol"), "/docs/final.md"),
+            Perms::READ | Perms::LIST
+        );
+        assert_eq!(perms(Some("alice"), "/a/b/c/k.key"), Perms::READ);
+    }
+
+    #[test]
+    fn outside_the_root_gets_nothing() {
+        let a = access(Some("alice"));
+        assert_eq!(a.perms(Path::new("/etc/passwd")), Perms::NONE);
+        assert_eq!(a.perms(Path::new("/srvx")), Perms::NONE);
+    }
+
+    #[test]
+    fn unmatched_paths_get_nothing() {
+        let acl = parse("[/pub]\n* = read", Path::new("/srv")).unwrap();
+        let a = Access::new(Some(Arc::new(acl)), None, Perms::ALL);
+        assert_eq!(a.perms(Path::new("/srv/pub/x")), Perms::READ);
+        assert_eq!(a.perms(Path::new("/srv/other")), Perms::NONE);
+        assert_eq!(a.perms(Path::new("/srv")), Perms::NONE);
+    }
+
+    #[test]
+    fn visibility_and_anywhere() {
+        let anon = access(None);
+        let root = Path::new("/srv");
+        assert!(anon.can_see(root, OsStr::new("pub"), true));
+        assert!(!anon.can_see(root, OsStr::new("private"), true));
+        assert!(!anon.can_see(&root.join("pub"), OsStr::new("x.key"), false));
+        assert!(!anon.anywhere(Perms::UPLOAD | Perms::ADMIN));
+        assert!(access(Some("dave")).anywhere(Perms::UPLOAD));
+        assert!(!access(Some("dave")).anywhere(Perms::ADMIN));
+        assert!(access(Some("alice")).anywhere(Perms::ADMIN));
+    }
+
+    #[test]
+    fn share_scope() {
+        let a = Access::share(PathBuf::from("/srv/pub"), Perms::READ | Perms::LIST);
+        assert!(a.allows(Path::new("/srv/pub/a.txt"), Perms::READ));
+        assert!(!a.allows(Path::new("/srv/pub/a.txt"), Perms::UPLOAD));
+        assert!(!a.allows(Path::new("/srv/public"), Perms::READ));
+        assert!(!a.allows(Path::new("/srv"), Perms::LIST));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let root = Path::new("/srv");
+        let cases = [
+            ("* = read", 1, "outside"),
+            ("[/]\n* = write", 2, "unknown permission"),
+            ("[/]\n@ghosts = read", 2, "unknown group"),
+            ("[relative]", 1, "must start with '/'"),
+            ("[/]\njust text", 2, "expected name = value"),
+            ("[/]\n = read", 2, "missing name"),
+            ("[/[]\n* = read", 1, "section"),
+        ];
+        for (text, line, message) in cases {
+            let Err((n, e)) = parse(text, root) else {
+                panic!("{text:?} parsed");
+            };
+            assert_eq!(n, line, "{text:?}");
+            assert!(e.contains(message), "{text:?}: {e}");
+        }
+        // groups may be defined after use
+        assert!(parse("[/]\n@late = read\n[groups]\nlate = x", root).is_ok());
+    }
+}
@@ -0,0 +1,372 @@
+// Streamed directory archives (?archive=zip / ?archive=tar.gz).
+//
+// The tree is planned up front (so limits can be enforced before any bytes go
+// out), then written by a blocking task straight into the response body
+// through a bounded channel. Nothing touches the disk besides the sources.
+
+use std::{
+    collections::HashSet,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use axum::body::{Body, Bytes};
+use futures_util::stream;
+use tokio::sync::mpsc;
+
+use crate::acl::{Access, Perms};
+use crate::STREAM_CHUNK_BYTES;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    TarGz,
+}
+
+impl Format {
+    pub fn from_query(value: &str) -> Option<Self> {
+        match value {
+            "zip" => Some(Format::Zip),
+            "tar.gz" | "tgz" | "targz" => Some(Format::TarGz),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Zip => "application/zip",
+            Format::TarGz => "application/gzip",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_bytes: u64,
+    pub max_entries: usize,
+}
+
+pub enum PlanError {
+    TooLarge,
+    TooManyEntries,
+    Unreadable,
+}
+
+struct Item {
+    name: String, // archive path, '/'-separated, prefixed with the top-level dir
+    path: PathBuf,
+    is_dir: bool,
+    size: u64,
+    mtime: Option<SystemTime>,
+    mode: u32,
+}
+
+pub struct Plan {
+    items: Vec<Item>,
+}
+
+// Walk `dir` and collect everything to archive. Entries (files or symlinks)
+// that resolve outside `root` are skipped, as are directories already visited
+// and entries `access` may not see, under either their own or their resolved
+// path, since the archive carries the contents.
+pub async fn plan(
+    root: &Path,
+    dir: &Path,
+    top: &str,
+    limits: Limits,
+    access: &Access,
+) -> Result<Plan, PlanError> {
+    let root = root.to_path_buf();
+    let dir = dir.to_path_buf();
+    let top = top.to_string();
+    let access = access.clone();
+    tokio::task::spawn_blocking(move || plan_blocking(&root, &dir, &top, limits, &access))
+        .await
+        .map_err(|_| PlanError::Unreadable)?
+}
+
+fn plan_blocking(
+    root: &Path,
+    dir: &Path,
+    top: &str,
+    limits: Limits,
+    access: &Access,
+) -> Result<Plan, PlanError> {
+    let mut items = Vec::new();
+    let mut total: u64 = 0;
+    let mut visited = HashSet::new();
+    visited.insert(dir.to_path_buf());
+
+    let mut stack = vec![(dir.to_path_buf(), top.to_string())];
+    while let Some((path, prefix)) = stack.pop() {
+        let meta = std::fs::metadata(&path).map_err(|_| PlanError::Unreadable)?;
+        items.push(Item {
+            name: format!("{prefix}/"),
+            path: path.clone(),
+            is_dir: true,
+            size: 0,
+            mtime: meta.modified().ok(),
+            mode: mode_of(&meta),
+        });
+
+        let Ok(rd) = std::fs::read_dir(&path) else {
+            continue;
+        };
+        let mut children: Vec<_> = rd.filter_map(Result::ok).collect();
+        children.sort_by_key(|e| e.file_name());
+
+        let mut subdirs = Vec::new();
+        for e in children {
+            let Ok(canon) = std::fs::canonicalize(e.path()) else {
+                continue;
+            };
+            if !canon.starts_with(root) {
+                continue;
+            }
+            let Ok(meta) = std::fs::metadata(&canon) else {
+                continue;
+            };
+            let need = if meta.is_dir() {
+                Perms::LIST
+            } else {
+                Perms::READ
+            };
+            if !access.can_see(&path, &e.file_name(), meta.is_dir()) || !access.allows(&canon, need)
+            {
+                continue;
+            }
+            let name = format!("{prefix}/{}", e.file_name().to_string_lossy());
+
+            if meta.is_dir() {
+                if visited.insert(canon.clone()) {
+                    subdirs.push((canon, name));
+                }
+                continue;
+            }
+            if !meta.is_file() {
+                continue;
+            }
+
+            total = total.saturating_add(meta.len());
+            if total > limits.max_bytes {
+                return Err(PlanError::TooLarge);
+            }
+            items.push(Item {
+                name,
+                path: canon,
+                is_dir: false,
+                size: meta.len(),
+                mtime: meta.modified().ok(),
+                mode: mode_of(&meta),
+            });
+            if items.len() > limits.max_entries {
+                return Err(PlanError::TooManyEntries);
+            }
+        }
+        stack.extend(subdirs.into_iter().rev());
+    }
+
+    Ok(Plan { items })
+}
+
+#[cfg(unix)]
+fn mode_of(meta: &std::fs::Metadata) -> u32 {
+    std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_of(meta: &std::fs::Metadata) -> u32 {
+    if meta.is_dir() {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+pub fn stream_body(format: Format, plan: Plan) -> Body {
+    let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(4);
+
+    tokio::task::spawn_blocking(move || {
+        let writer = ChannelWriter {
+            tx: tx.clone(),
+            buf: Vec::with_capacity(STREAM_CHUNK_BYTES),
+        };
+        let result = match format {
+            Format::Zip => write_zip(writer, &plan.items),
+            Format::TarGz => write_tar_gz(writer, &plan.items),
+        };
+        // Surface failures to hyper so the client sees a truncated transfer
+        // rather than a silently corrupt archive.
+        if let Err(e) = result {
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+
+    Body::from_stream(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
+
+fn write_zip(writer: ChannelWriter, items: &[Item]) -> io::Result<()> {
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    let mut zip = ZipWriter::new_stream(writer);
+    for item in items {
+        let mut opts = SimpleFileOptions::default().unix_permissions(item.mode);
+        if let Some(dt) = item.mtime.and_then(zip_datetime) {
+            opts = opts.last_modified_time(dt);
+        }
+
+        if item.is_dir {
+            // add_directory() sets the data-descriptor flag in stream mode but
+            // never writes the descriptor; an empty stored entry gets one.
+            let opts = opts
+                .compression_method(CompressionMethod::Stored)
+                .unix_permissions(0o40000 | item.mode);
+            zip.start_file(item.name.as_str(), opts)
+                .map_err(io::Error::other)?;
+            continue;
+        }
+
+        let mime = mime_guess::from_path(&item.path).first_or_octet_stream();
+        let method = if crate::compress::is_compressible(mime.as_ref()) {
+            CompressionMethod::Deflated
+        } else {
+            CompressionMethod::Stored
+        };
+        let opts = opts
+            .compression_method(method)
+            .large_file(item.size >= u32::MAX as u64);
+        zip.start_file(item.name.as_str(), opts)
+            .map_err(io::Error::other)?;
+        copy_exact(&item.path, item.size, &mut zip)?;
+    }
+    let mut inner = zip.finish().map_err(io::Error::other)?.into_inner();
+    inner.flush()
+}
+
+fn write_tar_gz(writer: ChannelWriter, items: &[Item]) -> io::Result<()> {
+    let gz = flate2::write::GzEncoder::new(writer, flate2::Compression::fast());
+    let mut tar = tar::Builder::new(gz);
+
+    for item in items {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(item.mode);
+        header.set_mtime(
+            item.mtime
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        );
+        if item.is_dir {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+            tar.append_data(&mut header, &item.name, io::empty())?;
+        } else {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(item.size);
+            let f = std::fs::File::open(&item.path)?;
+            tar.append_data(&mut header, &item.name, ExactReader::new(f, item.size))?;
+        }
+    }
+
+    let mut inner = tar.into_inner()?.finish()?;
+    inner.flush()
+}
+
+// Copy exactly `size` bytes; a file that changed size mid-download aborts the
+// archive instead of producing one with mismatched headers.
+fn copy_exact(path: &Path, size: u64, out: &mut impl Write) -> io::Result<()> {
+    let f = std::fs::File::open(path)?;
+    let copied = io::copy(&mut ExactReader::new(f, size), out)?;
+    if copied != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file changed while archiving",
+        ));
+    }
+    Ok(())
+}
+
+struct ExactReader {
+    inner: io::Take<std::fs::File>,
+    remaining: u64,
+}
+
+impl ExactReader {
+    fn new(f: std::fs::File, size: u64) -> Self {
+        Self {
+            inner: f.take(size),
+            remaining: size,
+        }
+    }
+}
+
+impl Read for ExactReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && self.remaining > 0 && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file changed while archiving",
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+fn zip_datetime(t: SystemTime) -> Option<zip::DateTime> {
+    let (y, mo, d, h, mi, s) = crate::listing::utc_parts(t)?;
+    zip::DateTime::from_date_and_time(
+        u16::try_from(y).ok()?,
+        mo as u8,
+        d as u8,
+        h as u8,
+        mi as u8,
+        s as u8,
+    )
+    .ok()
+}
+
+// Adapts the blocking archive writers to the async response body: bytes are
+// buffered into chunks and handed over a bounded channel, so a slow client
+// applies backpressure instead of growing memory.
+struct ChannelWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn send_buf(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(STREAM_CHUNK_BYTES));
+        self.tx
+            .blocking_send(Ok(Bytes::from(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= STREAM_CHUNK_BYTES {
+            self.send_buf()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buf()
+    }
+}
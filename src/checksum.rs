@@ -0,0 +1,275 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+
+// Drop the whole cache once it grows past this many entries; cheap and good
+// enough to keep memory bounded on very large trees.
+const MAX_CACHE_ENTRIES: usize = 16 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    Sha256,
+    Blake3,
+}
+
+impl Algorithm {
+    // "sha256" / "sha-256" / "blake3", case-insensitive.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "sha256" | "sha-256" => Some(Algorithm::Sha256),
+            "blake3" | "b3" => Some(Algorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Blake3 => "BLAKE3",
+        }
+    }
+
+    // File name of a directory manifest, as the usual tools name them.
+    pub fn manifest_name(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "SHA256SUMS",
+            Algorithm::Blake3 => "B3SUMS",
+        }
+    }
+}
+
+struct Entry {
+    len: u64,
+    mtime: Option<SystemTime>,
+    digest: Vec<u8>,
+}
+
+// File digests keyed by path, invalidated whenever size or mtime change.
+#[derive(Default)]
+pub struct ChecksumCache {
+    entries: Mutex<HashMap<(PathBuf, Algorithm), Entry>>,
+}
+
+impl ChecksumCache {
+    // The digest if it is already cached for this size and mtime; never hashes.
+    pub fn cached(
+        &self,
+        path: &Path,
+        len: u64,
+        mtime: Option<SystemTime>,
+        alg: Algorithm,
+    ) -> Option<Vec<u8>> {
+        let entries = self.entries.lock().unwrap();
+        let e = entries.get(&(path.to_path_buf(), alg))?;
+        (e.len == len && e.mtime == mtime).then(|| e.digest.clone())
+    }
+
+    pub async fn digest(
+        &self,
+        path: &Path,
+        meta: &std::fs::Metadata,
+        alg: Algorithm,
+    ) -> std::io::Result<Vec<u8>> {
+        let len = meta.len();
+        let mtime = meta.modified().ok();
+        let key = (path.to_path_buf(), alg);
+
+        if let Some(e) = self.entries.lock().unwrap().get(&key) {
+            if e.len == len && e.mtime == mtime {
+                return Ok(e.digest.clone());
+            }
+        }
+
+        let owned = path.to_path_buf();
+        let digest = tokio::task::spawn_blocking(move || hash_file(&owned, alg))
+            .await
+            .map_err(std::io::Error::other)??;
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_CACHE_ENTRIES {
+            entries.clear();
+        }
+        entries.insert(
+            key,
+            Entry {
+                len,
+                mtime,
+                digest: digest.clone(),
+            },
+        );
+        Ok(digest)
+    }
+}
+
+fn hash_file(path: &Path, alg: Algorithm) -> std::io::Result<Vec<u8>> {
+    let mut f = std::fs::File::open(path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    match alg {
+        Algorithm::Sha256 => {
+            let mut h = Sha256::new();
+            loop {
+                let n = f.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                h.update(&buf[..n]);
+            }
+            Ok(h.finalize().to_vec())
+        }
+        Algorithm::Blake3 => {
+            let mut h = blake3::Hasher::new();
+            loop {
+                let n = f.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                h.update(&buf[..n]);
+            }
+            Ok(h.finalize().as_bytes().to_vec())
+        }
+    }
+}
+
+// One line of a sha256sum/b3sum style manifest. Names containing '\\' or a
+// newline are escaped and the line prefixed with '\\', as coreutils does.
+pub fn manifest_line(digest: &[u8], name: &str) -> String {
+    if name.contains(['\\', '\n', '\r']) {
+        let escaped = name
+            .replace('\\', "\\\\")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r");
+        format!("\\{}  {escaped}\n", hex::encode(digest))
+    } else {
+        format!("{}  {name}\n", hex::encode(digest))
+    }
+}
+
+// Repr-Digest (RFC 9530) and legacy Digest (RFC 3230) header values. Only
+// SHA-256 is in the registries; there is no header form for BLAKE3.
+pub fn digest_headers(alg: Algorithm, digest: &[u8]) -> Option<(String, String)> {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    match alg {
+        Algorithm::Sha256 => {
+            let b64 = STANDARD.encode(digest);
+            Some((format!("sha-256=:{b64}:"), format!("SHA-256={b64}")))
+        }
+        Algorithm::Blake3 => None,
+    }
+}
+
+// True when the client asks for a SHA-256 digest via Want-Repr-Digest or
+// Want-Digest (any non-zero preference).
+pub fn wants_sha256(headers: &axum::http::HeaderMap) -> bool {
+    ["want-repr-digest", "want-digest"].iter().any(|name| {
+        headers
+            .get_all(*name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|item| {
+                let (alg, pref) = item.split_once(['=', ';']).unwrap_or((item, "1"));
+                let pref = pref.trim().trim_start_matches("q=");
+                alg.trim().eq_ignore_ascii_case("sha-256") && pref.trim() != "0"
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, HeaderValue};
+
+    #[test]
+    fn manifest_lines() {
+        let digest = [0xab, 0x01];
+        assert_eq!(manifest_line(&digest, "a b.txt"), "ab01  a b.txt\n");
+        assert_eq!(manifest_line(&digest, "dir/a.txt"), "ab01  dir/a.txt\n");
+        assert_eq!(manifest_line(&digest, "a\\b"), "\\ab01  a\\\\b\n");
+        assert_eq!(manifest_line(&digest, "a\nb"), "\\ab01  a\\nb\n");
+        assert_eq!(manifest_line(&digest, "a\r\\n"), "\\ab01  a\\r\\\\n\n");
+    }
+
+    #[test]
+    fn want_digest() {
+        let cases: [(&str, &str, bool); 12] = [
+            ("want-repr-digest", "sha-256=1", true),
+            ("want-repr-digest", "SHA-256=10", true),
+            ("want-repr-digest", "sha-512=3, sha-256=1", true),
+            ("want-repr-digest", "sha-256", true),
+            ("want-repr-digest", "sha-256=0", false),
+            ("want-repr-digest", "sha-256 = 0", false),
+            ("want-repr-digest", "sha-512=10", false),
+            ("want-repr-digest", "sha-2567=1", false),
+            ("want-digest", "SHA-256;q=0.3", true),
+            ("want-digest", "sha-256;q=0", false),
+            ("want-digest", "md5, sha-256", true),
+            ("digest", "sha-256", false),
+        ];
+        for (name, value, want) in cases {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            assert_eq!(wants_sha256(&headers), want, "{name}: {value}");
+        }
+        assert!(!wants_sha256(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn digest_header_values() {
+        let empty = Sha256::digest(b"").to_vec();
+        let (repr, legacy) = digest_headers(Algorithm::Sha256, &empty).unwrap();
+        assert_eq!(
+            repr,
+            "sha-256=:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=:"
+        );
+        assert_eq!(
+            legacy,
+            "SHA-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+        assert!(digest_headers(Algorithm::Blake3, &empty).is_none());
+    }
+
+    #[test]
+    fn algorithm_names() {
+        assert_eq!(Algorithm::parse(" SHA-256 "), Some(Algorithm::Sha256));
+        assert_eq!(Algorithm::parse("sha256"), Some(Algorithm::Sha256));
+        assert_eq!(Algorithm::parse("B3"), Some(Algorithm::Blake3));
+        assert_eq!(Algorithm::parse("md5"), None);
+    }
+
+    #[tokio::test]
+    async fn digests_are_cached_by_size_and_mtime() {
+        let path = std::env::temp_dir().join(format!("lantrix-{}-checksum", std::process::id()));
+        std::fs::write(&path, "abc").unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        let cache = ChecksumCache::default();
+
+        let sha = cache.digest(&path, &meta, Algorithm::Sha256).await.unwrap();
+        assert_eq!(
+            hex::encode(&sha),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let b3 = cache.digest(&path, &meta, Algorithm::Blake3).await.unwrap();
+        assert_eq!(
+            hex::encode(&b3),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+
+        let mtime = meta.modified().ok();
+        assert_eq!(cache.cached(&path, 3, mtime, Algorithm::Sha256), Some(sha));
+        assert_eq!(cache.cached(&path, 4, mtime, Algorithm::Sha256), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+// Content-Encoding negotiation and streaming compression.
+
+use async_compression::{
+    tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder},
+    Level,
+};
+use axum::{
+    body::Body,
+    http::{header, HeaderMap},
+};
+use tokio::io::AsyncBufRead;
+use tokio_util::io::ReaderStream;
+
+use crate::STREAM_CHUNK_BYTES;
+
+// Below this size compression rarely pays for the extra headers and CPU.
+pub const MIN_COMPRESS_BYTES: u64 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    // Server preference order when the client weighs encodings equally.
+    pub const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    // File suffix used for precompressed sidecars.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => ".br",
+            Encoding::Zstd => ".zst",
+            Encoding::Gzip => ".gz",
+        }
+    }
+}
+
+// Pick the best encoding from `available` acceptable to the client, honoring
+// q-values (q=0 means "not acceptable") and the `*` wildcard.
+pub fn negotiate(headers: &HeaderMap, available: &[Encoding]) -> Option<Encoding> {
+    let accept = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())?;
+
+    let mut wildcard: Option<f32> = None;
+    let mut prefs: Vec<(&str, f32)> = Vec::new();
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        if name.is_empty() {
+            continue;
+        }
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name == "*" {
+            wildcard = Some(q);
+        } else {
+            prefs.push((name, q));
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for enc in available {
+        let q = prefs
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(enc.as_str()))
+            .map(|(_, q)| *q)
+            .or(wildcard)
+            .unwrap_or(0.0);
+        if q <= 0.0 {
+            continue;
+        }
+        if best.is_none_or(|(_, bq)| q > bq) {
+            best = Some((*enc, q));
+        }
+    }
+    best.map(|(e, _)| e)
+}
+
+// Text-like types that benefit from compression. Already-compressed formats
+// (images, video, archives) are left alone.
+pub fn is_compressible(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or("").trim();
+    if essence.starts_with("text/") {
+        return true;
+    }
+    matches!(
+        essence,
+        "application/json"
+            | "application/javascript"
+            | "application/x-javascript"
+            | "application/xml"
+            | "application/xhtml+xml"
+            | "application/rss+xml"
+            | "application/atom+xml"
+            | "application/manifest+json"
+            | "application/ld+json"
+            | "application/wasm"
+            | "application/x-ndjson"
+            | "application/toml"
+            | "application/yaml"
+            | "image/svg+xml"
+            | "image/x-icon"
+            | "image/bmp"
+            | "font/ttf"
+            | "font/otf"
+    )
+}
+
+pub fn encode_body<R>(enc: Encoding, reader: R) -> Body
+where
+    R: AsyncBufRead + Send + Unpin + 'static,
+{
+    // Brotli's default quality (11) is far too slow for on-the-fly use.
+    match enc {
+        Encoding::Brotli => Body::from_stream(ReaderStream::with_capacity(
+            BrotliEncoder::with_quality(reader, Level::Precise(4)),
+            STREAM_CHUNK_BYTES,
+        )),
+        Encoding::Zstd => Body::from_stream(ReaderStream::with_capacity(
+            ZstdEncoder::new(reader),
+            STREAM_CHUNK_BYTES,
+        )),
+        Encoding::Gzip => Body::from_stream(ReaderStream::with_capacity(
+            GzipEncoder::new(reader),
+            STREAM_CHUNK_BYTES,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    use Encoding::{Brotli, Gzip, Zstd};
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn negotiation() {
+        let cases = [
+            ("gzip", Some(Gzip)),
+            ("GZIP", Some(Gzip)),
+            ("gzip, deflate, br, zstd", Some(Brotli)),
+            ("identity", None),
+            ("", None),
+            // q-values
+            ("br;q=0.5, gzip", Some(Gzip)),
+            ("gzip;q=0.8, zstd;q=0.9, br;q=0.1", Some(Zstd)),
+            ("br; q=0.5 , gzip ; q=0.7", Some(Gzip)),
+            ("gzip;q=abc", Some(Gzip)),
+            // q=0 is an exclusion
+            ("br;q=0, gzip;q=0", None),
+            ("br;q=0, *", Some(Zstd)),
+            ("*;q=0", None),
+            ("*;q=0, gzip", Some(Gzip)),
+            // `*` covers only what isn't named
+            ("*", Some(Brotli)),
+            ("gzip, *;q=0.5", Some(Gzip)),
+            ("gzip;q=0.4, *;q=0.5", Some(Brotli)),
+            // ties go to the server's preference
+            ("gzip;q=0.5, zstd;q=0.5", Some(Zstd)),
+            ("gzip, br", Some(Brotli)),
+        ];
+        for (value, want) in cases {
+            assert_eq!(negotiate(&accept(value), &Encoding::ALL), want, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn only_available_encodings() {
+        assert_eq!(negotiate(&accept("br, gzip;q=0.1"), &[Gzip]), Some(Gzip));
+        assert_eq!(negotiate(&accept("br"), &[Gzip, Zstd]), None);
+        assert_eq!(negotiate(&accept("*"), &[]), None);
+        assert_eq!(negotiate(&HeaderMap::new(), &Encoding::ALL), None);
+    }
+
+    #[test]
+    fn compressible_types() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/zip"));
+    }
+}
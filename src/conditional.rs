@@ -0,0 +1,267 @@
+// Validators (ETag / Last-Modified) and conditional request evaluation
+// (RFC 9110 section 13).
+
+use std::time::SystemTime;
+
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use httpdate::HttpDate;
+
+#[derive(Clone, Debug)]
+pub struct Validators {
+    pub etag: String, // quoted, optionally W/-prefixed
+    pub last_modified: Option<HttpDate>,
+}
+
+impl Validators {
+    pub fn new(etag: String, mtime: Option<SystemTime>) -> Self {
+        Self {
+            etag,
+            last_modified: mtime.map(HttpDate::from),
+        }
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if let Ok(v) = HeaderValue::from_str(&self.etag) {
+            headers.insert(header::ETAG, v);
+        }
+        if let Some(lm) = self.last_modified {
+            if let Ok(v) = HeaderValue::from_str(&lm.to_string()) {
+                headers.insert(header::LAST_MODIFIED, v);
+            }
+        }
+    }
+
+    // Each Content-Encoding is a distinct representation and needs its own tag.
+    pub fn for_encoding(&self, coding: &str) -> Self {
+        let etag = match self.etag.strip_suffix('"') {
+            Some(head) => format!("{head}-{coding}\""),
+            None => self.etag.clone(),
+        };
+        Self {
+            etag,
+            last_modified: self.last_modified,
+        }
+    }
+
+    fn is_weak(&self) -> bool {
+        self.etag.starts_with("W/")
+    }
+}
+
+// Strong ETag from inode, size and mtime. Cheap, and changes whenever the file
+// is rewritten or replaced.
+pub fn metadata_etag(meta: &std::fs::Metadata) -> String {
+    #[cfg(unix)]
+    let ino = std::os::unix::fs::MetadataExt::ino(meta);
+    #[cfg(not(unix))]
+    let ino = 0u64;
+
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+
+    format!("\"{ino:x}-{:x}-{mtime:x}\"", meta.len())
+}
+
+pub fn digest_etag(digest: &[u8]) -> String {
+    let n = digest.len().min(16);
+    format!("\"{}\"", hex::encode(&digest[..n]))
+}
+
+pub fn weak_etag(bytes: &[u8]) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut h = std::collections::hash_map::DefaultHasher::new();
+    bytes.hash(&mut h);
+    format!("W/\"{:x}-{:x}\"", bytes.len(), h.finish())
+}
+
+// Returns the status to answer with when a precondition short-circuits the
+// request (304 or 412), or None to proceed normally. Only used for GET/HEAD.
+pub fn evaluate(headers: &HeaderMap, v: &Validators) -> Option<StatusCode> {
+    if let Some(im) = header_str(headers, header::IF_MATCH) {
+        if !etag_list_matches(im, v, true) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    } else if let Some(ius) = header_date(headers, header::IF_UNMODIFIED_SINCE) {
+        if let Some(lm) = v.last_modified {
+            if lm > ius {
+                return Some(StatusCode::PRECONDITION_FAILED);
+            }
+        }
+    }
+
+    if let Some(inm) = header_str(headers, header::IF_NONE_MATCH) {
+        if etag_list_matches(inm, v, false) {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    } else if let Some(ims) = header_date(headers, header::IF_MODIFIED_SINCE) {
+        if let Some(lm) = v.last_modified {
+            if lm <= ims {
+                return Some(StatusCode::NOT_MODIFIED);
+            }
+        }
+    }
+
+    None
+}
+
+// If-Range: the Range header only applies when the validator still matches,
+// using strong comparison for entity tags and exact match for dates.
+pub fn if_range_matches(headers: &HeaderMap, v: &Validators) -> bool {
+    let Some(value) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let Ok(value) = value.to_str() else {
+        return false;
+    };
+    let value = value.trim();
+
+    if value.starts_with('"') || value.starts_with("W/") {
+        return !v.is_weak() && !value.starts_with("W/") && value == v.etag;
+    }
+
+    match (value.parse::<HttpDate>(), v.last_modified) {
+        (Ok(d), Some(lm)) => d == lm,
+        _ => false,
+    }
+}
+
+fn etag_list_matches(list: &str, v: &Validators, strong: bool) -> bool {
+    let list = list.trim();
+    if list == "*" {
+        return true;
+    }
+    if strong && v.is_weak() {
+        return false;
+    }
+    let ours = opaque_tag(&v.etag);
+    list.split(',').map(str::trim).any(|tag| {
+        if strong && tag.starts_with("W/") {
+            return false;
+        }
+        opaque_tag(tag) == ours
+    })
+}
+
+fn opaque_tag(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn header_date(headers: &HeaderMap, name: header::HeaderName) -> Option<HttpDate> {
+    header_str(headers, name).and_then(|s| s.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const ETAG: &str = "\"abc\"";
+
+    fn mtime(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn date(secs: u64) -> String {
+        HttpDate::from(mtime(secs)).to_string()
+    }
+
+    fn validators() -> Validators {
+        Validators::new(ETAG.to_string(), Some(mtime(1_000_000)))
+    }
+
+    type Pairs<'a> = Vec<(header::HeaderName, &'a str)>;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        for (name, value) in pairs {
+            h.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        h
+    }
+
+    #[test]
+    fn evaluate_preconditions() {
+        use header::{IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_UNMODIFIED_SINCE};
+        let (older, same, newer) = (date(999_999), date(1_000_000), date(1_000_001));
+        let nm = Some(StatusCode::NOT_MODIFIED);
+        let pf = Some(StatusCode::PRECONDITION_FAILED);
+        let cases: Vec<(Pairs, Option<StatusCode>)> = vec![
+            (vec![], None),
+            (vec![(IF_NONE_MATCH, ETAG)], nm),
+            (vec![(IF_NONE_MATCH, "\"x\", \"abc\"")], nm),
+            (vec![(IF_NONE_MATCH, "W/\"abc\"")], nm),
+            (vec![(IF_NONE_MATCH, "*")], nm),
+            (vec![(IF_NONE_MATCH, "\"x\"")], None),
+            (vec![(IF_MODIFIED_SINCE, &same)], nm),
+            (vec![(IF_MODIFIED_SINCE, &newer)], nm),
+            (vec![(IF_MODIFIED_SINCE, &older)], None),
+            (vec![(IF_MODIFIED_SINCE, "not a date")], None),
+            // If-None-Match takes precedence over If-Modified-Since
+            (
+                vec![(IF_NONE_MATCH, "\"x\""), (IF_MODIFIED_SINCE, &newer)],
+                None,
+            ),
+            (vec![(IF_NONE_MATCH, ETAG), (IF_MODIFIED_SINCE, &older)], nm),
+            (vec![(IF_MATCH, ETAG)], None),
+            (vec![(IF_MATCH, "*")], None),
+            (vec![(IF_MATCH, "\"x\"")], pf),
+            (vec![(IF_MATCH, "W/\"abc\"")], pf),
+            (vec![(IF_UNMODIFIED_SINCE, &same)], None),
+            (vec![(IF_UNMODIFIED_SINCE, &older)], pf),
+            // If-Match takes precedence over If-Unmodified-Since
+            (vec![(IF_MATCH, ETAG), (IF_UNMODIFIED_SINCE, &older)], None),
+            (vec![(IF_MATCH, "\"x\""), (IF_UNMODIFIED_SINCE, &newer)], pf),
+            // 412 is decided before 304
+            (vec![(IF_MATCH, "\"x\""), (IF_NONE_MATCH, ETAG)], pf),
+        ];
+        for (pairs, want) in cases {
+            assert_eq!(evaluate(&headers(&pairs), &validators()), want, "{pairs:?}");
+        }
+    }
+
+    #[test]
+    fn weak_validators_fail_if_match() {
+        let v = Validators::new("W/\"abc\"".to_string(), None);
+        let h = headers(&[(header::IF_MATCH, "W/\"abc\"")]);
+        assert_eq!(evaluate(&h, &v), Some(StatusCode::PRECONDITION_FAILED));
+        let h = headers(&[(header::IF_NONE_MATCH, "\"abc\"")]);
+        assert_eq!(evaluate(&h, &v), Some(StatusCode::NOT_MODIFIED));
+    }
+
+    #[test]
+    fn if_range() {
+        let v = validators();
+        let cases = [
+            (ETAG.to_string(), true),
+            ("\"x\"".to_string(), false),
+            ("W/\"abc\"".to_string(), false),
+            (date(1_000_000), true),
+            (date(999_999), false),
+            ("garbage".to_string(), false),
+        ];
+        for (value, want) in cases {
+            let h = headers(&[(header::IF_RANGE, &value)]);
+            assert_eq!(if_range_matches(&h, &v), want, "{value}");
+        }
+        assert!(if_range_matches(&HeaderMap::new(), &v));
+
+        let weak = Validators::new("W/\"abc\"".to_string(), None);
+        let h = headers(&[(header::IF_RANGE, "W/\"abc\"")]);
+        assert!(!if_range_matches(&h, &weak));
+    }
+
+    #[test]
+    fn encoding_variants_get_distinct_tags() {
+        assert_eq!(validators().for_encoding("br").etag, "\"abc-br\"");
+        let weak = Validators::new("W/\"abc\"".to_string(), None);
+        assert_eq!(weak.for_encoding("gzip").etag, "W/\"abc-gzip\"");
+    }
+}
@@ -0,0 +1,100 @@
+// Custom error pages. Handlers build errors with `error()`, which tags the
+// response with its message; `render()` then swaps the body for a template
+// ({code}.html) or a JSON document, keeping status and headers intact.
+
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::html_escape;
+
+#[derive(Clone)]
+pub struct ErrorMessage(pub Cow<'static, str>);
+
+pub fn error(status: StatusCode, message: impl Into<Cow<'static, str>>) -> Response {
+    let message = message.into();
+    let mut resp = (status, message.clone()).into_response();
+    resp.extensions_mut().insert(ErrorMessage(message));
+    resp
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    status: u16,
+    error: &'a str,
+    message: &'a str,
+    path: &'a str,
+}
+
+// Where {code}.html templates are looked up (canonicalized).
+#[derive(Clone)]
+pub struct ErrorPages {
+    pub dir: PathBuf,
+}
+
+impl ErrorPages {
+    pub async fn render(&self, headers: &HeaderMap, path: &str, resp: Response) -> Response {
+        let Some(ErrorMessage(message)) = resp.extensions().get::<ErrorMessage>().cloned() else {
+            return resp;
+        };
+        let status = resp.status();
+        let reason = status.canonical_reason().unwrap_or("Error");
+        let path = format!("/{}", path.trim_start_matches('/'));
+
+        let (mut parts, _) = resp.into_parts();
+
+        if crate::prefers_json(headers) {
+            let body = serde_json::to_vec(&ErrorBody {
+                status: status.as_u16(),
+                error: reason,
+                message: &message,
+                path: &path,
+            })
+            .unwrap_or_default();
+            parts.headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            parts.headers.remove(header::CONTENT_LENGTH);
+            return Response::from_parts(parts, Body::from(body));
+        }
+
+        let Some(template) = self.load(status).await else {
+            return Response::from_parts(parts, Body::from(message));
+        };
+
+        let html = template
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", &html_escape(reason))
+            .replace("{{message}}", &html_escape(&message))
+            .replace("{{path}}", &html_escape(&path));
+
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Response::from_parts(parts, Body::from(html))
+    }
+
+    async fn load(&self, status: StatusCode) -> Option<String> {
+        let candidate = self.dir.join(format!("{}.html", status.as_u16()));
+        let canon = tokio::fs::canonicalize(&candidate).await.ok()?;
+        if !canon.starts_with(&self.dir) || !is_file(&canon).await {
+            return None;
+        }
+        tokio::fs::read_to_string(&canon).await.ok()
+    }
+}
+
+async fn is_file(p: &Path) -> bool {
+    tokio::fs::metadata(p).await.is_ok_and(|m| m.is_file())
+}
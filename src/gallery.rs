@@ -0,0 +1,327 @@
+// Gallery support: server-side thumbnails (?thumb) and EXIF summaries (?exif=1)
+// for image files. Thumbnails are cached in memory, and optionally on disk with
+// --thumb-cache, keyed by path, size, length and mtime so edits invalidate them.
+
+use std::{
+    collections::HashMap,
+    io::{BufReader, Cursor},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use axum::body::Bytes;
+use image::{
+    codecs::jpeg::JpegEncoder, DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+
+pub const THUMB_SIZES: [u32; 3] = [128, 256, 512];
+pub const DEFAULT_THUMB_SIZE: u32 = 256;
+
+// Sources above these limits are not decoded.
+const MAX_SOURCE_BYTES: u64 = 64 * 1024 * 1024;
+const MAX_DIMENSION: u32 = 16_384;
+
+// Drop the in-memory cache once it holds this many bytes of thumbnails.
+const MAX_MEMORY_BYTES: usize = 64 * 1024 * 1024;
+
+const JPEG_QUALITY: u8 = 80;
+
+// Formats we can decode (and therefore thumbnail).
+pub fn is_image(mime: &str) -> bool {
+    matches!(
+        mime,
+        "image/jpeg" | "image/png" | "image/webp" | "image/gif"
+    )
+}
+
+// ?thumb (default size) or ?thumb=N, rounded up to the nearest supported size.
+pub fn thumb_size(value: &str) -> u32 {
+    let wanted = value.parse::<u32>().unwrap_or(DEFAULT_THUMB_SIZE);
+    THUMB_SIZES
+        .into_iter()
+        .find(|s| *s >= wanted)
+        .unwrap_or(THUMB_SIZES[THUMB_SIZES.len() - 1])
+}
+
+#[derive(Clone)]
+pub struct Thumbnail {
+    pub bytes: Bytes,
+    pub content_type: &'static str,
+}
+
+impl Thumbnail {
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        // Opaque images are encoded as JPEG, ones with alpha as PNG.
+        let content_type = if bytes.starts_with(b"\x89PNG") {
+            "image/png"
+        } else {
+            "image/jpeg"
+        };
+        Self {
+            bytes: Bytes::from(bytes),
+            content_type,
+        }
+    }
+}
+
+struct Entry {
+    len: u64,
+    mtime: Option<SystemTime>,
+    thumb: Thumbnail,
+}
+
+#[derive(Default)]
+struct Memory {
+    entries: HashMap<(PathBuf, u32), Entry>,
+    bytes: usize,
+}
+
+pub struct ThumbnailCache {
+    memory: Mutex<Memory>,
+    disk: Option<PathBuf>,
+    // Decoding is CPU- and memory-hungry; cap how many run at once.
+    permits: Semaphore,
+}
+
+impl ThumbnailCache {
+    pub fn new(disk: Option<PathBuf>) -> Self {
+        let workers = std::thread::available_parallelism().map_or(2, |n| n.get());
+        Self {
+            memory: Mutex::new(Memory::default()),
+            disk,
+            permits: Semaphore::new(workers),
+        }
+    }
+
+    pub async fn get(
+        &self,
+        path: &Path,
+        meta: &std::fs::Metadata,
+        size: u32,
+    ) -> Result<Thumbnail, String> {
+        let len = meta.len();
+        let mtime = meta.modified().ok();
+        let key = (path.to_path_buf(), size);
+
+        if let Some(e) = self.memory.lock().unwrap().entries.get(&key) {
+            if e.len == len && e.mtime == mtime {
+                return Ok(e.thumb.clone());
+            }
+        }
+        if len > MAX_SOURCE_BYTES {
+            return Err("image too large".to_string());
+        }
+
+        let disk_path = self
+            .disk
+            .as_ref()
+            .map(|dir| dir.join(disk_key(path, len, mtime, size)));
+
+        let cached = match &disk_path {
+            Some(p) => tokio::fs::read(p).await.ok(),
+            None => None,
+        };
+        let bytes = match cached {
+            Some(bytes) => bytes,
+            None => {
+                let _permit = self.permits.acquire().await.map_err(|e| e.to_string())?;
+                let owned = path.to_path_buf();
+                let bytes = tokio::task::spawn_blocking(move || generate(&owned, size))
+                    .await
+                    .map_err(|e| e.to_string())??;
+                if let Some(p) = &disk_path {
+                    write_atomic(p, &bytes).await;
+                }
+                bytes
+            }
+        };
+
+        let thumb = Thumbnail::from_bytes(bytes);
+        let mut memory = self.memory.lock().unwrap();
+        if memory.bytes + thumb.bytes.len() > MAX_MEMORY_BYTES {
+            memory.entries.clear();
+            memory.bytes = 0;
+        }
+        memory.bytes += thumb.bytes.len();
+        if let Some(old) = memory.entries.insert(
+            key,
+            Entry {
+                len,
+                mtime,
+                thumb: thumb.clone(),
+            },
+        ) {
+            memory.bytes -= old.thumb.bytes.len();
+        }
+        Ok(thumb)
+    }
+}
+
+fn disk_key(path: &Path, len: u64, mtime: Option<SystemTime>, size: u32) -> String {
+    let nanos = mtime
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let mut h = Sha256::new();
+    h.update(path.as_os_str().as_encoded_bytes());
+    h.update(format!("\0{len}\0{nanos}\0{size}").as_bytes());
+    format!("{}.thumb", hex::encode(&h.finalize()[..16]))
+}
+
+// Best effort: a failed write only means the next request regenerates.
+async fn write_atomic(path: &Path, bytes: &[u8]) {
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    if tokio::fs::write(&tmp, bytes).await.is_ok() && tokio::fs::rename(&tmp, path).await.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+}
+
+fn generate(path: &Path, size: u32) -> Result<Vec<u8>, String> {
+    let mut reader = ImageReader::open(path)
+        .and_then(ImageReader::with_guessed_format)
+        .map_err(|e| e.to_string())?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder().map_err(|e| e.to_string())?;
+    let orientation = decoder
+        .orientation()
+        .unwrap_or(image::metadata::Orientation::NoTransforms);
+    let mut img = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+    img.apply_orientation(orientation);
+
+    let thumb = img.thumbnail(size, size);
+    let mut out = Cursor::new(Vec::new());
+    if thumb.color().has_alpha() {
+        thumb
+            .write_to(&mut out, ImageFormat::Png)
+            .map_err(|e| e.to_string())?;
+    } else {
+        JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY)
+            .encode_image(&thumb.to_rgb8())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(out.into_inner())
+}
+
+#[derive(Serialize)]
+pub struct ImageInfo {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub exif: Vec<ExifField>,
+}
+
+#[derive(Serialize)]
+pub struct ExifField {
+    pub label: &'static str,
+    pub value: String,
+}
+
+// What the lightbox shows, in display order.
+const EXIF_TAGS: &[(exif::Tag, &str)] = &[
+    (exif::Tag::Make, "Camera make"),
+    (exif::Tag::Model, "Camera model"),
+    (exif::Tag::LensModel, "Lens"),
+    (exif::Tag::DateTimeOriginal, "Taken"),
+    (exif::Tag::ExposureTime, "Exposure"),
+    (exif::Tag::FNumber, "Aperture"),
+    (exif::Tag::PhotographicSensitivity, "ISO"),
+    (exif::Tag::FocalLength, "Focal length"),
+    (exif::Tag::ExposureBiasValue, "Exposure bias"),
+    (exif::Tag::Flash, "Flash"),
+    (exif::Tag::WhiteBalance, "White balance"),
+    (exif::Tag::Software, "Software"),
+    (exif::Tag::Artist, "Artist"),
+    (exif::Tag::Copyright, "Copyright"),
+    (exif::Tag::ImageDescription, "Description"),
+];
+
+pub async fn image_info(path: &Path) -> Result<ImageInfo, String> {
+    let owned = path.to_path_buf();
+    tokio::task::spawn_blocking(move || read_info(&owned))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+fn read_info(path: &Path) -> Result<ImageInfo, String> {
+    let dims = ImageReader::open(path)
+        .and_then(ImageReader::with_guessed_format)
+        .map_err(|e| e.to_string())?
+        .into_dimensions()
+        .ok();
+
+    // Images without EXIF (or in a container without it, like GIF) just get
+    // their dimensions.
+    let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let data = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok();
+
+    let mut fields = Vec::new();
+    let mut rotated = false;
+    if let Some(data) = &data {
+        for (tag, label) in EXIF_TAGS {
+            if let Some(f) = data.get_field(*tag, exif::In::PRIMARY) {
+                let value = f.display_value().with_unit(data).to_string();
+                let value = value.trim_matches('"').trim().to_string();
+                if !value.is_empty() {
+                    fields.push(ExifField { label, value });
+                }
+            }
+        }
+        if let Some(gps) = gps_position(data) {
+            fields.push(ExifField {
+                label: "Location",
+                value: gps,
+            });
+        }
+        // Orientations 5-8 swap width and height when displayed.
+        rotated = data
+            .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+            .and_then(|f| f.value.get_uint(0))
+            .is_some_and(|o| (5..=8).contains(&o));
+    }
+
+    let (width, height) = match dims {
+        Some((w, h)) if rotated => (Some(h), Some(w)),
+        Some((w, h)) => (Some(w), Some(h)),
+        None => (None, None),
+    };
+    Ok(ImageInfo {
+        width,
+        height,
+        exif: fields,
+    })
+}
+
+// "48.858370, 2.294481" from the GPS IFD, if both coordinates are present.
+fn gps_position(data: &exif::Exif) -> Option<String> {
+    let coord = |value_tag, ref_tag| -> Option<f64> {
+        let field = data.get_field(value_tag, exif::In::PRIMARY)?;
+        let exif::Value::Rational(parts) = &field.value else {
+            return None;
+        };
+        let [d, m, s] = parts.as_slice() else {
+            return None;
+        };
+        let degrees = d.to_f64() + m.to_f64() / 60.0 + s.to_f64() / 3600.0;
+        let reference = data
+            .get_field(ref_tag, exif::In::PRIMARY)
+            .map(|f| f.display_value().to_string())
+            .unwrap_or_default();
+        Some(if reference.contains(['S', 'W']) {
+            -degrees
+        } else {
+            degrees
+        })
+    };
+    let lat = coord(exif::Tag::GPSLatitude, exif::Tag::GPSLatitudeRef)?;
+    let lon = coord(exif::Tag::GPSLongitude, exif::Tag::GPSLongitudeRef)?;
+    Some(format!("{lat:.6}, {lon:.6}"))
+}
@@ -0,0 +1,268 @@
+// --auth-file: Apache htpasswd files ("user:hash" per line, '#' comments).
+// Supported hashes are bcrypt ($2a$/$2b$/$2y$, htpasswd -B), SHA-crypt
+// ($5$/$6$) and argon2 ($argon2id$ etc.); other schemes are skipped with a
+// warning. The file is re-read when its size or mtime changes, so users can
+// be added or removed without a restart.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use sha2::{Digest, Sha256};
+
+// Successful verifications are remembered (by user and SHA-256 of the
+// password) so a page with many assets doesn't pay for bcrypt on every
+// request. Cleared on reload and when it grows past this size.
+const MAX_VERIFIED: usize = 1024;
+
+// Unknown users are checked against this (htpasswd -B default cost), so the
+// response time doesn't reveal which user names exist.
+const DUMMY_HASH: &str = "$2y$05$kskkEUbvb7gplHiP.hALC.Nm7fUT7PjBTqGa.dHAOl1YguXsCcdh.";
+
+pub struct Htpasswd {
+    path: PathBuf,
+    state: Mutex<Loaded>,
+}
+
+struct Loaded {
+    len: u64,
+    mtime: Option<SystemTime>,
+    users: HashMap<String, String>,
+    verified: HashSet<(String, [u8; 32])>,
+}
+
+impl Htpasswd {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let meta = std::fs::metadata(path)
+            .map_err(|e| format!("cannot read --auth-file {}: {e}", path.display()))?;
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read --auth-file {}: {e}", path.display()))?;
+        let users = parse(&text, path);
+        if users.is_empty() {
+            return Err(format!(
+                "--auth-file {} has no usable users",
+                path.display()
+            ));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Mutex::new(Loaded {
+                len: meta.len(),
+                mtime: meta.modified().ok(),
+                users,
+                verified: HashSet::new(),
+            }),
+        })
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.state.lock().unwrap().users.len()
+    }
+
+    pub async fn has_user(&self, user: &str) -> bool {
+        self.reload_if_changed().await;
+        self.state.lock().unwrap().users.contains_key(user)
+    }
+
+    pub async fn verify(&self, user: &str, pass: &str) -> bool {
+        self.reload_if_changed().await;
+
+        let key = (user.to_string(), Sha256::digest(pass.as_bytes()).into());
+        let hash = {
+            let state = self.state.lock().unwrap();
+            if state.verified.contains(&key) {
+                return true;
+            }
+            state.users.get(user).cloned()
+        };
+
+        let known = hash.is_some();
+        let hash = hash.unwrap_or_else(|| DUMMY_HASH.to_string());
+        let owned = pass.to_string();
+        let ok = tokio::task::spawn_blocking(move || verify_hash(&hash, &owned))
+            .await
+            .unwrap_or(false)
+            && known;
+        if ok {
+            let mut state = self.state.lock().unwrap();
+            if state.verified.len() >= MAX_VERIFIED {
+                state.verified.clear();
+            }
+            state.verified.insert(key);
+        }
+        ok
+    }
+
+    // A file that disappears or fails to parse keeps the previous users, so
+    // a half-written edit doesn't lock everyone out.
+    async fn reload_if_changed(&self) {
+        let Ok(meta) = tokio::fs::metadata(&self.path).await else {
+            return;
+        };
+        let (len, mtime) = (meta.len(), meta.modified().ok());
+        {
+            let state = self.state.lock().unwrap();
+            if state.len == len && state.mtime == mtime {
+                return;
+            }
+        }
+        let Ok(text) = tokio::fs::read_to_string(&self.path).await else {
+            return;
+        };
+        let users = parse(&text, &self.path);
+        let mut state = self.state.lock().unwrap();
+        state.len = len;
+        state.mtime = mtime;
+        if users.is_empty() {
+            eprintln!(
+                "auth-file {}: no usable users, keeping the previous ones",
+                self.path.display()
+            );
+            return;
+        }
+        println!("Reloaded {} ({} users)", self.path.display(), users.len());
+        state.users = users;
+        state.verified.clear();
+    }
+}
+
+fn parse(text: &str, path: &Path) -> HashMap<String, String> {
+    let mut users = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((user, hash)) = line.split_once(':') else {
+            eprintln!("{}:{}: expected user:hash", path.display(), i + 1);
+            continue;
+        };
+        if !is_supported(hash) {
+            eprintln!(
+                "{}:{}: unsupported hash for {user:?} (use bcrypt, SHA-crypt or argon2)",
+                path.display(),
+                i + 1
+            );
+            continue;
+        }
+        users.insert(user.to_string(), hash.to_string());
+    }
+    users
+}
+
+fn is_supported(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$", "$5$", "$6$", "$argon2"]
+        .iter()
+        .any(|p| hash.starts_with(p))
+}
+
+fn verify_hash(hash: &str, pass: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        return PasswordHash::new(hash).is_ok_and(|h| {
+            Argon2::default()
+                .verify_password(pass.as_bytes(), &h)
+                .is_ok()
+        });
+    }
+    if hash.starts_with("$2") {
+        return pwhash::bcrypt::verify(pass, hash);
+    }
+    if hash.starts_with("$5$") {
+        return pwhash::sha256_crypt::verify(pass, hash);
+    }
+    if hash.starts_with("$6$") {
+        return pwhash::sha512_crypt::verify(pass, hash);
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "pwb" (bcrypt, cost 5), "pwa" (argon2id), "secret5" / "secret6" (SHA-crypt)
+    const BCRYPT: &str = "$2y$05$kskkEUbvb7gplHiP.hALC.Nm7fUT7PjBTqGa.dHAOl1YguXsCcdh.";
+    const ARGON2: &str = "$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHRzb21lc2FsdA$g24SJoICGnEdFSWEu+/livwkK2O4ECgaf2xhO48G9/0";
+    const SHA256: &str = "$5$lantrixsalt$0356QqdgJdUuGw8EKIH3lMLlh6T9n2giwoOT3//7Kx1";
+    const SHA512: &str = "$6$lantrixsalt$s3qjY5AsyG6XOneD0ycCMWBLGR3L7NmD.HdLYwVnxrbuPSNVdFxlvjUFiTOhu1AjEdvY8gNEtgp1FwIz4Zrb11";
+
+    #[test]
+    fn verifies_each_scheme() {
+        let cases = [
+            (BCRYPT, "pwb"),
+            (&BCRYPT.replacen("$2y$", "$2b$", 1), "pwb"),
+            (ARGON2, "pwa"),
+            (SHA256, "secret5"),
+            (SHA512, "secret6"),
+        ];
+        for (hash, pass) in cases {
+            assert!(verify_hash(hash, pass), "{hash}");
+            assert!(!verify_hash(hash, "wrong"), "{hash}");
+            assert!(!verify_hash(hash, ""), "{hash}");
+        }
+        assert!(!verify_hash("$1$salt$hash", "pwb"));
+        assert!(!verify_hash("$argon2id$garbage", "pwa"));
+    }
+
+    #[test]
+    fn parse_skips_unusable_lines() {
+        let text = format!(
+            "# comment\n\
+             \n\
+             bee:{BCRYPT}\n\
+             arg:{ARGON2}\n\
+             five:{SHA256}\n\
+             six:{SHA512}\n\
+             md5:$apr1$salt$hash\n\
+             sha1:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n\
+             plain:secret\n\
+             no-colon\n  \
+             spaced:{BCRYPT}  \n"
+        );
+        let users = parse(&text, Path::new("test"));
+        let mut names: Vec<&str> = users.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, ["arg", "bee", "five", "six", "spaced"]);
+        assert_eq!(users["spaced"], BCRYPT);
+    }
+
+    fn temp_file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lantrix-{}-{name}", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn verify_and_reload() {
+        let path = temp_file("htpasswd", &format!("bee:{BCRYPT}\narg:{ARGON2}\n"));
+        let file = Htpasswd::load(&path).unwrap();
+        assert_eq!(file.user_count(), 2);
+        assert!(file.verify("bee", "pwb").await);
+        assert!(file.verify("bee", "pwb").await); // cached
+        assert!(!file.verify("bee", "pwa").await);
+        assert!(!file.verify("arg", "pwb").await);
+        // the dummy hash unknown users are checked against is never enough
+        assert!(!file.verify("nobody", "pwb").await);
+
+        // a different size is enough to trigger the reload
+        std::fs::write(&path, format!("arg:{ARGON2}\n\n")).unwrap();
+        assert!(!file.verify("bee", "pwb").await);
+        assert!(file.verify("arg", "pwa").await);
+        assert!(!file.has_user("bee").await);
+        assert!(file.has_user("arg").await);
+
+        // an unusable file keeps the previous users
+        std::fs::write(&path, "bee:plaintext\n").unwrap();
+        assert!(file.verify("arg", "pwa").await);
+
+        std::fs::remove_file(&path).unwrap();
+
+        let empty = temp_file("empty", "# nobody\n");
+        assert!(Htpasswd::load(&empty).is_err());
+        std::fs::remove_file(&empty).unwrap();
+    }
+}
@@ -0,0 +1,764 @@
+// Directory entry collection, sorting and formatting helpers shared by the
+// HTML listing and the console.
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use serde::Serialize;
+
+use crate::acl::Access;
+
+// Hard limits for recursive JSON listings.
+pub const MAX_RECURSIVE_DEPTH: usize = 32;
+pub const MAX_RECURSIVE_ENTRIES: usize = 100_000;
+
+// Page sizes for HTML and JSON listings (?limit=).
+pub const DEFAULT_PAGE_SIZE: usize = 1_000;
+pub const MAX_PAGE_SIZE: usize = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub name: String,
+    // The file name as the OS returned it; `name` is its lossy UTF-8 form.
+    pub raw_name: OsString,
+    pub is_dir: bool,
+    pub size: u64,
+    pub mtime: Option<SystemTime>,
+}
+
+impl Entry {
+    // Percent-encoded from the raw name, so names that are not valid UTF-8
+    // still link to the right file.
+    pub fn href(&self) -> String {
+        encode_segment(&self.raw_name)
+    }
+
+    pub fn mime(&self) -> Option<String> {
+        if self.is_dir {
+            return None;
+        }
+        Some(
+            mime_guess::from_path(&self.name)
+                .first_or_octet_stream()
+                .essence_str()
+                .to_string(),
+        )
+    }
+
+    pub fn icon(&self) -> &'static str {
+        if self.is_dir {
+            return "📁";
+        }
+        let mime = self.mime().unwrap_or_default();
+        let (top, sub) = mime.split_once('/').unwrap_or(("", ""));
+        match (top, sub) {
+            ("image", _) => "🖼️",
+            ("video", _) => "🎞️",
+            ("audio", _) => "🎵",
+            ("text", _) => "📄",
+            ("application", "pdf") => "📕",
+            ("application", "json" | "xml" | "javascript" | "toml" | "yaml") => "📄",
+            (
+                "application",
+                "zip" | "gzip" | "x-tar" | "x-bzip2" | "x-xz" | "x-7z-compressed" | "zstd"
+                | "vnd.rar" | "x-rar-compressed",
+            ) => "📦",
+            _ => "📎",
+        }
+    }
+}
+
+// Reads all entries of `dir` that `access` may see. Also returns the newest mtime among the
+// directory and its entries, which is what Last-Modified means for a listing.
+pub async fn read_entries(
+    dir: &Path,
+    access: &Access,
+) -> std::io::Result<(Vec<Entry>, Option<SystemTime>)> {
+    let mut entries = read_names(dir, access).await?;
+    let latest = tokio::fs::metadata(dir)
+        .await
+        .ok()
+        .and_then(|m| m.modified().ok());
+    let newest = stat_entries(dir, &mut entries).await;
+    Ok((entries, latest.max(newest)))
+}
+
+// Names and types of the entries of `dir` without stat()ing them (size and
+// mtime are left empty), so huge directories can be sorted by name and paged
+// before any per-entry metadata is read. Only symlinks are resolved, to learn
+// whether they point at a directory. Entries `access` may not see are left
+// out here, so every listing, search and manifest agrees on them.
+pub async fn read_names(dir: &Path, access: &Access) -> std::io::Result<Vec<Entry>> {
+    let mut rd = tokio::fs::read_dir(dir).await?;
+    let mut entries = Vec::new();
+    while let Ok(Some(e)) = rd.next_entry().await {
+        entries.extend(visible_entry(dir, &e, access).await);
+    }
+    Ok(entries)
+}
+
+// One `read_dir` entry as `read_names` returns it, or None if `access` may
+// not see it.
+pub async fn visible_entry(dir: &Path, e: &tokio::fs::DirEntry, access: &Access) -> Option<Entry> {
+    let raw_name = e.file_name();
+    let is_dir = match e.file_type().await {
+        Ok(t) if t.is_symlink() => tokio::fs::metadata(e.path())
+            .await
+            .is_ok_and(|m| m.is_dir()),
+        Ok(t) => t.is_dir(),
+        Err(_) => false,
+    };
+    if !access.can_see(dir, &raw_name, is_dir) {
+        return None;
+    }
+    Some(Entry {
+        name: raw_name.to_string_lossy().to_string(),
+        raw_name,
+        is_dir,
+        size: 0,
+        mtime: None,
+    })
+}
+
+// All entries of `dir` in `sort` order. Only the size and mtime orderings
+// stat every entry; otherwise callers stat just the page they show.
+pub async fn read_sorted(dir: &Path, sort: Sort, access: &Access) -> std::io::Result<Vec<Entry>> {
+    let mut entries = read_names(dir, access).await?;
+    if sort.needs_metadata() {
+        stat_entries(dir, &mut entries).await;
+    }
+    sort_entries(&mut entries, sort);
+    Ok(entries)
+}
+
+// Fills in size and mtime for entries of `dir` from `read_names`, returning
+// the newest mtime among them.
+pub async fn stat_entries(dir: &Path, entries: &mut [Entry]) -> Option<SystemTime> {
+    let mut latest = None;
+    for e in entries {
+        stat_entry(dir, e).await;
+        latest = latest.max(e.mtime);
+    }
+    latest
+}
+
+pub async fn stat_entry(dir: &Path, e: &mut Entry) {
+    let path = dir.join(&e.raw_name);
+    // Follow symlinks for size/mtime, like serving does.
+    let meta = match tokio::fs::metadata(&path).await {
+        Ok(m) => Some(m),
+        Err(_) => tokio::fs::symlink_metadata(&path).await.ok(),
+    };
+    if let Some(m) = meta {
+        e.is_dir = m.is_dir();
+        e.size = m.len();
+        e.mtime = m.modified().ok();
+    }
+}
+
+// An entry found by `walk`: its path relative to the walked directory, both
+// for display and percent-encoded for URLs, and its location on disk.
+pub struct Walked {
+    pub rel: String,
+    pub href: String,
+    pub path: PathBuf,
+    pub entry: Entry,
+}
+
+// Recursively lists `dir` (depth 1 = direct children only), sorting each
+// directory level. Symlinked directories are followed only when they resolve
+// inside `root`, and each directory is visited at most once. Returns the
+// entries, whether the cap was hit, and the newest mtime seen.
+pub async fn walk(
+    root: &Path,
+    dir: &Path,
+    max_depth: usize,
+    sort: Sort,
+    access: &Access,
+) -> std::io::Result<(Vec<Walked>, bool, Option<SystemTime>)> {
+    let mut out = Vec::new();
+    let mut latest = None;
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    visited.insert(dir.to_path_buf());
+
+    // Depth-first, keeping each directory's children in sorted order.
+    let mut stack: Vec<(PathBuf, String, String, usize)> =
+        vec![(dir.to_path_buf(), String::new(), String::new(), 1)];
+    let mut first = true;
+    while let Some((path, prefix, href_prefix, depth)) = stack.pop() {
+        let (mut entries, dir_latest) = match read_entries(&path, access).await {
+            Ok(r) => r,
+            Err(e) if first => return Err(e),
+            Err(_) => continue,
+        };
+        first = false;
+        latest = latest.max(dir_latest);
+        sort_entries(&mut entries, sort);
+
+        let mut subdirs = Vec::new();
+        for e in entries {
+            if out.len() >= MAX_RECURSIVE_ENTRIES {
+                return Ok((out, true, latest));
+            }
+            let rel = format!("{prefix}{}", e.name);
+            let href = format!("{href_prefix}{}", e.href());
+            let full = path.join(&e.raw_name);
+            if e.is_dir && depth < max_depth {
+                if let Ok(canon) = tokio::fs::canonicalize(&full).await {
+                    if canon.starts_with(root) && visited.insert(canon.clone()) {
+                        subdirs.push((canon, format!("{rel}/"), format!("{href}/"), depth + 1));
+                    }
+                }
+            }
+            out.push(Walked {
+                rel,
+                href,
+                path: full,
+                entry: e,
+            });
+        }
+        stack.extend(subdirs.into_iter().rev());
+    }
+    Ok((out, false, latest))
+}
+
+#[derive(Serialize)]
+pub struct JsonEntry {
+    pub name: String,
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub size: u64,
+    pub mtime: Option<String>,
+    pub mime: Option<String>,
+    pub url: String,
+}
+
+// Opaque ?cursor= token for the JSON listing: the sort key of the last entry
+// of a page. The next page starts after that key, so entries added or
+// removed meanwhile don't shift it the way an offset would.
+pub struct Cursor {
+    is_dir: bool,
+    size: u64,
+    mtime: Option<Duration>, // since the epoch
+    name: String,
+}
+
+impl Cursor {
+    pub fn after(e: &Entry) -> Self {
+        Self {
+            is_dir: e.is_dir,
+            size: e.size,
+            mtime: e
+                .mtime
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok()),
+            name: e.name.clone(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mtime = self
+            .mtime
+            .map(|d| format!("{}.{:09}", d.as_secs(), d.subsec_nanos()))
+            .unwrap_or_default();
+        let raw = format!(
+            "{}:{}:{mtime}:{}",
+            u8::from(self.is_dir),
+            self.size,
+            self.name
+        );
+        URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    pub fn decode(token: &str) -> Option<Self> {
+        let raw = String::from_utf8(URL_SAFE_NO_PAD.decode(token).ok()?).ok()?;
+        let mut parts = raw.splitn(4, ':');
+        let is_dir = match parts.next()? {
+            "0" => false,
+            "1" => true,
+            _ => return None,
+        };
+        let size = parts.next()?.parse().ok()?;
+        let mtime = match parts.next()? {
+            "" => None,
+            t => {
+                let (secs, nanos) = t.split_once('.')?;
+                Some(Duration::new(secs.parse().ok()?, nanos.parse().ok()?))
+            }
+        };
+        let name = parts.next()?.to_string();
+        Some(Self {
+            is_dir,
+            size,
+            mtime,
+            name,
+        })
+    }
+
+    // Index of the first entry in `sorted` (ordered by `sort`) that comes
+    // after the cursor.
+    pub fn position(&self, sorted: &[Entry], sort: Sort) -> usize {
+        let key = Entry {
+            name: self.name.clone(),
+            raw_name: OsString::from(&self.name),
+            is_dir: self.is_dir,
+            size: self.size,
+            mtime: self.mtime.map(|d| SystemTime::UNIX_EPOCH + d),
+        };
+        sorted.partition_point(|e| compare(e, &key, sort) != Ordering::Greater)
+    }
+}
+
+// ?cursor= for unsorted (?sort=none) JSON listings, which have no sort key
+// to resume after: the number of visible entries already returned. Relies
+// on the directory order staying the same between requests, which holds
+// while the directory isn't modified.
+pub fn encode_offset(n: usize) -> String {
+    URL_SAFE_NO_PAD.encode(format!("@{n}"))
+}
+
+pub fn decode_offset(token: &str) -> Option<usize> {
+    let raw = String::from_utf8(URL_SAFE_NO_PAD.decode(token).ok()?).ok()?;
+    raw.strip_prefix('@')?.parse().ok()
+}
+
+impl JsonEntry {
+    // `dir_url` is the URL of the listed directory, ending in '/'.
+    pub fn new(dir_url: &str, w: &Walked) -> Self {
+        let e = &w.entry;
+        let mut url = format!("{dir_url}{}", w.href);
+        if e.is_dir {
+            url.push('/');
+        }
+        Self {
+            name: e.name.clone(),
+            path: w.rel.clone(),
+            kind: if e.is_dir { "dir" } else { "file" },
+            size: e.size,
+            mtime: e.mtime.map(format_rfc3339),
+            mime: e.mime(),
+            url,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct JsonListing {
+    pub path: String,
+    pub entries: Vec<JsonEntry>,
+    pub truncated: bool,
+}
+
+// ?page=N (1-based) and ?limit=M for listings, clamped to sane values.
+pub fn page_from_query(query: &HashMap<String, String>) -> (usize, usize) {
+    let page = query
+        .get("page")
+        .and_then(|p| p.parse::<usize>().ok())
+        .unwrap_or(1)
+        .max(1);
+    let limit = query
+        .get("limit")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    (page, limit)
+}
+
+// URL path for a location under the served root ("/a%20b/c/"), mounted at
+// `prefix` ("" or "/mount").
+pub fn url_for(prefix: &str, root: &Path, path: &Path, is_dir: bool) -> String {
+    let rel = path.strip_prefix(root).unwrap_or(Path::new(""));
+    let mut url = format!("{prefix}/");
+    let segments: Vec<String> = rel.iter().map(encode_segment).collect();
+    url.push_str(&segments.join("/"));
+    if is_dir && !url.ends_with('/') {
+        url.push('/');
+    }
+    url
+}
+
+// Percent-encode one path segment. On Unix the raw bytes are encoded, so
+// non-UTF-8 names round-trip through the URL.
+#[cfg(unix)]
+pub fn encode_segment(name: &OsStr) -> String {
+    urlencoding::encode_binary(std::os::unix::ffi::OsStrExt::as_bytes(name)).into_owned()
+}
+
+#[cfg(not(unix))]
+pub fn encode_segment(name: &OsStr) -> String {
+    urlencoding::encode(&name.to_string_lossy()).into_owned()
+}
+
+// Inverse of `encode_segment` for a whole request path: percent-decodes the
+// bytes and maps them onto a relative filesystem path. None when the result
+// cannot name a file on this platform.
+#[cfg(unix)]
+pub fn decode_path(encoded: &str) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    let bytes = urlencoding::decode_binary(encoded.as_bytes());
+    Some(PathBuf::from(OsStr::from_bytes(&bytes)))
+}
+
+#[cfg(not(unix))]
+pub fn decode_path(encoded: &str) -> Option<PathBuf> {
+    urlencoding::decode(encoded)
+        .ok()
+        .map(|s| PathBuf::from(s.into_owned()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Mtime,
+    Type,
+}
+
+impl SortKey {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Mtime => "mtime",
+            SortKey::Type => "type",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sort {
+    pub key: SortKey,
+    pub desc: bool,
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Self {
+            key: SortKey::Name,
+            desc: false,
+        }
+    }
+}
+
+impl Sort {
+    // Size and mtime orderings need every entry stat()ed before paging.
+    pub fn needs_metadata(self) -> bool {
+        matches!(self.key, SortKey::Size | SortKey::Mtime)
+    }
+
+    // ?sort=name|size|mtime|type&order=asc|desc; unknown values fall back to name/asc.
+    pub fn from_query(query: &HashMap<String, String>) -> Self {
+        let key = match query.get("sort").map(String::as_str) {
+            Some("size") => SortKey::Size,
+            Some("mtime") => SortKey::Mtime,
+            Some("type") => SortKey::Type,
+            _ => SortKey::Name,
+        };
+        let desc = query.get("order").map(String::as_str) == Some("desc");
+        Self { key, desc }
+    }
+}
+
+// Directories always come first; within each group entries are ordered by the
+// requested key, with natural name order as the tie-breaker.
+pub fn sort_entries(entries: &mut [Entry], sort: Sort) {
+    entries.sort_by(|a, b| compare(a, b, sort));
+}
+
+pub fn compare(a: &Entry, b: &Entry, sort: Sort) -> Ordering {
+    b.is_dir.cmp(&a.is_dir).then_with(|| {
+        let ord = match sort.key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Mtime => a.mtime.cmp(&b.mtime),
+            SortKey::Type => a.mime().cmp(&b.mime()),
+        }
+        .then_with(|| natural_cmp(&a.name, &b.name));
+        if sort.desc {
+            ord.reverse()
+        } else {
+            ord
+        }
+    })
+}
+
+// "file2" < "file10": runs of digits compare numerically, text compares
+// case-insensitively, and byte order breaks remaining ties.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut ai, mut bi) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (ai.peek().copied(), bi.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let xs = take_digits(&mut ai);
+                let ys = take_digits(&mut bi);
+                let xt = xs.trim_start_matches('0');
+                let yt = ys.trim_start_matches('0');
+                let ord = xt.len().cmp(&yt.len()).then_with(|| xt.cmp(yt));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ord = x.to_lowercase().cmp(y.to_lowercase());
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                ai.next();
+                bi.next();
+            }
+        }
+    }
+}
+
+fn take_digits(it: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut s = String::new();
+    while let Some(c) = it.peek().copied().filter(|c| c.is_ascii_digit()) {
+        s.push(c);
+        it.next();
+    }
+    s
+}
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+// "YYYY-MM-DD HH:MM" in UTC.
+pub fn format_mtime(t: SystemTime) -> String {
+    match utc_parts(t) {
+        Some((y, m, d, hh, mm, _)) => format!("{y:04}-{m:02}-{d:02} {hh:02}:{mm:02}"),
+        None => "-".to_string(),
+    }
+}
+
+// "YYYY-MM-DDTHH:MM:SSZ"
+pub fn format_rfc3339(t: SystemTime) -> String {
+    let (y, m, d, hh, mm, ss) = utc_parts(t).unwrap_or((1970, 1, 1, 0, 0, 0));
+    format!("{y:04}-{m:02}-{d:02}T{hh:02}:{mm:02}:{ss:02}Z")
+}
+
+// (year, month, day, hour, minute, second) in UTC; None before the epoch.
+pub fn utc_parts(t: SystemTime) -> Option<(i64, u32, u32, u32, u32, u32)> {
+    let secs = t.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs() as i64;
+    let rem = secs.rem_euclid(86_400) as u32;
+    let (y, m, d) = civil_from_days(secs.div_euclid(86_400));
+    Some((y, m, d, rem / 3600, (rem % 3600) / 60, rem % 60))
+}
+
+// Howard Hinnant's days-to-civil algorithm.
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn natural_order() {
+        let ordered = [
+            ("file2", "file10"),
+            ("file02", "file10"),
+            ("a", "B"),
+            ("B", "c"),
+            ("x9y", "x10a"),
+            ("img", "img1"),
+            ("v1.9", "v1.10"),
+            ("00000000000000000000001", "2"),
+        ];
+        for (a, b) in ordered {
+            assert_eq!(natural_cmp(a, b), Ordering::Less, "{a} < {b}");
+            assert_eq!(natural_cmp(b, a), Ordering::Greater, "{b} > {a}");
+        }
+        // equal ignoring case and leading zeros: byte order decides
+        assert_eq!(natural_cmp("File", "file"), Ordering::Less);
+        assert_eq!(natural_cmp("a01", "a1"), Ordering::Less);
+        assert_eq!(natural_cmp("same", "same"), Ordering::Equal);
+    }
+
+    #[test]
+    fn directories_first() {
+        let entry = |name: &str, is_dir: bool, size: u64| Entry {
+            name: name.to_string(),
+            raw_name: OsString::from(name),
+            is_dir,
+            size,
+            mtime: None,
+        };
+        let mut entries = vec![
+            entry("b.txt", false, 1),
+            entry("z", true, 0),
+            entry("a.txt", false, 5),
+            entry("a", true, 0),
+        ];
+        let names = |entries: &[Entry]| entries.iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+
+        sort_entries(&mut entries, Sort::default());
+        assert_eq!(names(&entries), ["a", "z", "a.txt", "b.txt"]);
+
+        let by_size_desc = Sort {
+            key: SortKey::Size,
+            desc: true,
+        };
+        sort_entries(&mut entries, by_size_desc);
+        assert_eq!(names(&entries), ["z", "a", "a.txt", "b.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn decode_path_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+        use std::path::Component;
+
+        let components = |encoded: &str| -> Vec<Vec<u8>> {
+            decode_path(encoded)
+                .unwrap()
+                .components()
+                .map(|c| c.as_os_str().as_bytes().to_vec())
+                .collect()
+        };
+        assert_eq!(components("a%20b/c"), [b"a b".to_vec(), b"c".to_vec()]);
+        // an encoded slash is still a separator once decoded
+        assert_eq!(components("a%2Fb"), [b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(components("a%2fb"), [b"a".to_vec(), b"b".to_vec()]);
+        // invalid UTF-8 survives as raw bytes
+        assert_eq!(components("%FF%FEx"), [vec![0xff, 0xfe, b'x']]);
+        // stray '%' is kept literally
+        assert_eq!(components("100%"), [b"100%".to_vec()]);
+
+        // encoded dots decode to parent components, which callers must
+        // reject or canonicalize; they aren't hidden as literal names
+        for encoded in ["..", "%2e%2e", "%2E%2E", "a/%2e%2e/%2e%2e", "a%2F..%2F.."] {
+            let path = decode_path(encoded).unwrap();
+            assert!(
+                path.components().any(|c| c == Component::ParentDir),
+                "{encoded}"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn encode_segment_round_trips() {
+        use std::os::unix::ffi::OsStrExt;
+
+        for raw in [
+            &b"plain.txt"[..],
+            b"a b#?%",
+            b"caf\xc3\xa9",
+            b"\xff\xfe",
+            b"50%+x",
+        ] {
+            let name = OsStr::from_bytes(raw);
+            let encoded = encode_segment(name);
+            assert!(encoded.bytes().all(|b| b.is_ascii_graphic()), "{encoded}");
+            assert!(!encoded.contains(['/', '?', '#']), "{encoded}");
+            assert_eq!(decode_path(&encoded).unwrap().as_os_str(), name);
+        }
+    }
+
+    #[test]
+    fn url_for_prefix() {
+        let root = Path::new("/srv");
+        assert_eq!(url_for("", root, Path::new("/srv"), true), "/");
+        assert_eq!(
+            url_for("/files", root, Path::new("/srv/a b/c.txt"), false),
+            "/files/a%20b/c.txt"
+        );
+        assert_eq!(
+            url_for("/files", root, Path::new("/srv/d"), true),
+            "/files/d/"
+        );
+    }
+
+    fn file(name: &str, size: u64, mtime: u64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            raw_name: OsString::from(name),
+            is_dir: false,
+            size,
+            mtime: Some(SystemTime::UNIX_EPOCH + Duration::new(mtime, 5)),
+        }
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let e = file("a:b:c.txt", 42, 1_700_000_000);
+        let token = Cursor::after(&e).encode();
+        let decoded = Cursor::decode(&token).unwrap();
+        assert_eq!(decoded.name, "a:b:c.txt");
+        assert_eq!(decoded.size, 42);
+        assert_eq!(decoded.mtime, Some(Duration::new(1_700_000_000, 5)));
+        assert!(!decoded.is_dir);
+
+        for bad in ["", "!!!", "MjpmOjo", &URL_SAFE_NO_PAD.encode("0:x::n")] {
+            assert!(Cursor::decode(bad).is_none(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn cursor_resumes_after_its_key() {
+        let mut entries: Vec<Entry> = ["f1", "f2", "f3", "f10"]
+            .iter()
+            .map(|n| file(n, 1, 0))
+            .collect();
+        let sort = Sort::default();
+        sort_entries(&mut entries, sort);
+
+        let after_f2 = Cursor::after(&entries[1]);
+        assert_eq!(after_f2.position(&entries, sort), 2);
+
+        // the entry it was taken from is gone: resume at the next key
+        let mut without_f2 = entries.clone();
+        without_f2.remove(1);
+        assert_eq!(after_f2.position(&without_f2, sort), 1);
+
+        // an entry sorting before the cursor appears: nothing is repeated
+        let mut with_new = entries.clone();
+        with_new.push(file("f0", 1, 0));
+        sort_entries(&mut with_new, sort);
+        assert_eq!(with_new[after_f2.position(&with_new, sort)].name, "f3");
+
+        let last = Cursor::after(&entries[3]);
+        assert_eq!(last.position(&entries, sort), entries.len());
+    }
+
+    #[test]
+    fn offset_cursor() {
+        assert_eq!(decode_offset(&encode_offset(0)), Some(0));
+        assert_eq!(decode_offset(&encode_offset(12_345)), Some(12_345));
+        assert_eq!(
+            decode_offset(&Cursor::after(&file("x", 0, 0)).encode()),
+            None
+        );
+        assert_eq!(decode_offset("not base64!"), None);
+    }
+}
@@ -0,0 +1,257 @@
+// Brute-force protection for Basic Auth. Failed attempts are counted per
+// client IP, per user name from that IP, and per user name from anywhere;
+// after a few free attempts each further failure doubles a lockout window
+// (capped), during which matching requests get 429 with Retry-After. A
+// success clears all three. The per-name count catches guessing one
+// account's password from many addresses; it allows more attempts and locks
+// for less long, since anyone can run it up to lock the real user out. The
+// table is capped in size, so made-up names can't grow it without bound.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const FREE_ATTEMPTS: u32 = 5;
+const BASE_LOCKOUT: Duration = Duration::from_secs(1);
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+// For a user name across all clients.
+const USER_FREE_ATTEMPTS: u32 = 20;
+const USER_MAX_LOCKOUT: Duration = Duration::from_secs(60);
+
+// Failure counts are forgotten after this long without a new failure.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+// Most records kept. When full, forgotten records are swept, and if that
+// isn't enough the eighth with the oldest failures is dropped.
+const MAX_ENTRIES: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    UserFrom(IpAddr, String),
+    User(String),
+}
+
+impl Key {
+    // Free attempts and longest lockout.
+    fn limits(&self) -> (u32, Duration) {
+        match self {
+            Key::User(_) => (USER_FREE_ATTEMPTS, USER_MAX_LOCKOUT),
+            _ => (FREE_ATTEMPTS, MAX_LOCKOUT),
+        }
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Key::Ip(ip) => write!(f, "client {ip}"),
+            Key::UserFrom(ip, user) => write!(f, "user {user:?} from {ip}"),
+            Key::User(user) => write!(f, "user {user:?}"),
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct Lockout {
+    failures: Mutex<HashMap<Key, Failures>>,
+}
+
+impl Lockout {
+    // Err(time left) when the IP or the user is currently locked out.
+    pub fn check(&self, ip: IpAddr, user: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        let wait = keys(ip, user)
+            .iter()
+            .filter_map(|k| failures.get(k)?.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max();
+        match wait {
+            Some(wait) => Err(wait),
+            None => Ok(()),
+        }
+    }
+
+    pub fn record_failure(&self, ip: IpAddr, user: &str) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        for key in keys(ip, user) {
+            if !failures.contains_key(&key) && failures.len() >= MAX_ENTRIES {
+                make_room(&mut failures, now);
+            }
+            let f = failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            if now.duration_since(f.last) >= FORGET_AFTER {
+                f.count = 0;
+            }
+            f.count += 1;
+            f.last = now;
+            let (free, max) = key.limits();
+            if f.count >= free {
+                let exp = (f.count - free).min(20);
+                let lockout = (BASE_LOCKOUT * 2u32.pow(exp)).min(max);
+                f.locked_until = Some(now + lockout);
+                eprintln!(
+                    "auth: locking out {key} for {}s after {} failed attempts",
+                    lockout.as_secs(),
+                    f.count
+                );
+            }
+        }
+    }
+
+    pub fn record_success(&self, ip: IpAddr, user: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        for key in keys(ip, user) {
+            failures.remove(&key);
+        }
+    }
+}
+
+fn keys(ip: IpAddr, user: &str) -> [Key; 3] {
+    [
+        Key::Ip(ip),
+        Key::UserFrom(ip, user.to_string()),
+        Key::User(user.to_string()),
+    ]
+}
+
+fn make_room(failures: &mut HashMap<Key, Failures>, now: Instant) {
+    failures.retain(|_, f| now.duration_since(f.last) < FORGET_AFTER);
+    if failures.len() < MAX_ENTRIES {
+        return;
+    }
+    let mut by_age: Vec<(Instant, Key)> =
+        failures.iter().map(|(k, f)| (f.last, k.clone())).collect();
+    let n = MAX_ENTRIES / 8;
+    by_age.select_nth_unstable_by_key(n, |(last, _)| *last);
+    for (_, key) in &by_age[..n] {
+        failures.remove(key);
+    }
+}
+
+// Equality that takes the same time wherever the inputs differ. Both sides
+// are hashed first so the length of the secret doesn't leak either.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    use sha2::{Digest, Sha256};
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn constant_time_eq_compares_contents() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret "));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn backoff_doubles_after_free_attempts() {
+        let lockout = Lockout::default();
+        for _ in 1..FREE_ATTEMPTS {
+            lockout.record_failure(ip(1), "alice");
+            assert!(lockout.check(ip(1), "alice").is_ok());
+        }
+        let mut previous = Duration::ZERO;
+        for n in 0..4 {
+            lockout.record_failure(ip(1), "alice");
+            let wait = lockout.check(ip(1), "alice").unwrap_err();
+            let expected = BASE_LOCKOUT * 2u32.pow(n);
+            assert!(wait <= expected && wait > expected / 2, "{n}: {wait:?}");
+            assert!(wait > previous);
+            previous = wait;
+        }
+        // the IP is locked for every user, other clients are not
+        assert!(lockout.check(ip(1), "bob").is_err());
+        assert!(lockout.check(ip(2), "alice").is_ok());
+
+        lockout.record_success(ip(1), "alice");
+        assert!(lockout.check(ip(1), "alice").is_ok());
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let lockout = Lockout::default();
+        for _ in 0..100 {
+            lockout.record_failure(ip(1), "alice");
+        }
+        let wait = lockout.check(ip(1), "alice").unwrap_err();
+        assert!(wait <= MAX_LOCKOUT && wait > MAX_LOCKOUT / 2);
+    }
+
+    #[test]
+    fn success_for_another_user_keeps_the_user_record() {
+        let lockout = Lockout::default();
+        for _ in 0..FREE_ATTEMPTS {
+            lockout.record_failure(ip(1), "victim");
+        }
+        // logging in as someone else clears the IP, not the guesses
+        // against the victim from that IP
+        lockout.record_success(ip(1), "attacker");
+        assert!(lockout.check(ip(1), "attacker").is_ok());
+        assert!(lockout.check(ip(1), "victim").is_err());
+    }
+
+    #[test]
+    fn user_is_locked_across_clients() {
+        let lockout = Lockout::default();
+        // one guess per address never trips the per-client limits
+        for i in 1..USER_FREE_ATTEMPTS {
+            lockout.record_failure(ip(i as u8), "alice");
+            assert!(lockout.check(ip(200), "alice").is_ok());
+        }
+        lockout.record_failure(ip(100), "alice");
+        let wait = lockout.check(ip(200), "alice").unwrap_err();
+        assert!(wait <= BASE_LOCKOUT);
+        assert!(lockout.check(ip(200), "bob").is_ok());
+
+        // and the lockout others can cause stays short
+        for i in 0..100 {
+            lockout.record_failure(ip(i), "alice");
+        }
+        let wait = lockout.check(ip(200), "alice").unwrap_err();
+        assert!(wait <= USER_MAX_LOCKOUT && wait > USER_MAX_LOCKOUT / 2);
+
+        lockout.record_success(ip(200), "alice");
+        assert!(lockout.check(ip(200), "alice").is_ok());
+    }
+
+    #[test]
+    fn table_is_bounded() {
+        let lockout = Lockout::default();
+        for i in 0..MAX_ENTRIES * 2 {
+            lockout.record_failure(ip(1), &format!("user{i}"));
+        }
+        for i in 0..MAX_ENTRIES {
+            let addr = IpAddr::from([10, 0, (i >> 8) as u8, i as u8]);
+            lockout.record_failure(addr, "alice");
+        }
+        assert!(lockout.failures.lock().unwrap().len() <= MAX_ENTRIES);
+    }
+}
@@ -1,18 +1,19 @@
 use std::{
+    collections::HashMap,
     net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     sync::Arc,
 };
 
 use axum::{
-    body::Body,
-    extract::{Extension as Ext, Multipart, Path as AxumPath},
-    http::{header, HeaderMap, StatusCode},
+    body::{Body, Bytes, HttpBody},
+    extract::{ConnectInfo, Extension as Ext, Form, Multipart, OriginalUri, Query},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
     response::{Html, IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use axum_server::tls_rustls::RustlsConfig;
 
@@ -21,9 +22,49 @@ use base64::Engine as _;
 
 use serde::{Deserialize, Serialize};
 
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use futures_util::{stream, StreamExt};
+
+mod acl;
+mod archive;
+mod checksum;
+mod compress;
+mod conditional;
+mod error_page;
+mod gallery;
+mod htpasswd;
+mod listing;
+mod lockout;
+mod markdown;
+mod range;
+mod search;
+mod session;
+mod share;
+mod theme;
+
+use acl::{Access, Acl, Perms};
+use checksum::ChecksumCache;
+use compress::Encoding;
+use conditional::Validators;
+use error_page::{error, ErrorMessage, ErrorPages};
+use gallery::ThumbnailCache;
+use htpasswd::Htpasswd;
+use listing::Sort;
+use lockout::Lockout;
+use range::{ByteRange, RangeOutcome};
+use session::Sessions;
+use share::Shares;
+use theme::{GalleryContext, ListingContext, LoginContext, MarkdownContext, Readme, Theme};
 
 const MAX_UPLOAD_BYTES: u64 = 50 * 1024 * 1024; // 50 MiB
+const STREAM_CHUNK_BYTES: usize = 64 * 1024; // 64 KiB
+
+// Allow header values for OPTIONS responses
+const ALLOW_READ: &str = "GET, HEAD, OPTIONS";
+const ALLOW_POST: &str = "POST, OPTIONS";
+const ALLOW_READ_POST: &str = "GET, HEAD, POST, OPTIONS";
 
 #[derive(Parser, Debug)]
 #[command(name = "lantrix", about = "Serve a directory over HTTP/HTTPS (with directory listings)")]
@@ -45,6 +86,26 @@ struct Args {
     #[arg(long = "auth")]
     auth: Option<String>,
 
+    /// Apache htpasswd file with one user:hash per line (bcrypt, SHA-crypt or argon2),
+    /// re-read when it changes. Keeps passwords off the command line.
+    #[arg(long = "auth-file", value_name = "FILE", conflicts_with = "auth")]
+    auth_file: Option<PathBuf>,
+
+    /// Per-path access rules: [/path] or [/glob] sections granting users (name, @group,
+    /// @authenticated, or * for anyone) read, list, upload or admin. The last matching
+    /// section decides; paths no section matches are refused.
+    #[arg(long = "acl", value_name = "FILE")]
+    acl: Option<PathBuf>,
+
+    /// Sign in through a login page and session cookie instead of the browser's Basic Auth
+    /// prompt (needs --auth or --auth-file). Basic Auth still works for scripts.
+    #[arg(long = "login-form")]
+    login_form: bool,
+
+    /// How long a --login-form session lasts: 30m, 12h, 7d
+    #[arg(long = "session-ttl", value_name = "DURATION", default_value = "12h", value_parser = parse_duration)]
+    session_ttl: std::time::Duration,
+
     /// Serve HTTPS only (self-signed certificate generated at startup)
     #[arg(long = "https")]
     https: bool,
@@ -58,19 +119,157 @@ struct Args {
     /// Enable a restricted web console UI at /__console
     #[arg(long = "console")]
     console: bool,
+
+    /// Derive file ETags from a SHA-256 of the contents instead of inode/size/mtime.
+    /// Hashes are cached per path and recomputed when size or mtime change.
+    #[arg(long = "etag-hash")]
+    etag_hash: bool,
+
+    /// Compress text-like responses on the fly (gzip, brotli or zstd, per Accept-Encoding)
+    #[arg(long = "compress")]
+    compress: bool,
+
+    /// Serve precompressed siblings (file.br, file.zst, file.gz) when the client accepts them
+    #[arg(long = "precompressed")]
+    precompressed: bool,
+
+    /// Single-page-app mode: serve this file (relative to the root, default index.html)
+    /// for unknown extension-less paths requested by browsers (Accept: text/html)
+    #[arg(long = "spa", value_name = "FALLBACK", num_args = 0..=1, default_missing_value = "index.html")]
+    spa: Option<PathBuf>,
+
+    /// Directory holding custom error pages (401.html, 403.html, 404.html, 500.html, ...).
+    /// Defaults to the served directory. Templates may use {{status}}, {{reason}},
+    /// {{message}} and {{path}}.
+    #[arg(long = "error-pages", value_name = "DIR")]
+    error_pages: Option<PathBuf>,
+
+    /// Clean URLs: serve about.html at /about, and 301-redirect /about.html to /about
+    /// and /dir/index.html to /dir/
+    #[arg(long = "clean-urls")]
+    clean_urls: bool,
+
+    /// Comma-separated index file names tried, in order, for directory requests
+    /// Example: --index index.html,default.htm,README.html
+    #[arg(
+        long = "index",
+        value_name = "NAMES",
+        value_delimiter = ',',
+        default_value = "index.html,index.htm"
+    )]
+    index: Vec<String>,
+
+    /// Refuse directory archive downloads (?archive=zip|tar.gz) larger than this many bytes
+    #[arg(
+        long = "archive-max-bytes",
+        value_name = "BYTES",
+        default_value_t = 8 * 1024 * 1024 * 1024
+    )]
+    archive_max_bytes: u64,
+
+    /// Refuse directory archive downloads with more than this many files
+    #[arg(
+        long = "archive-max-entries",
+        value_name = "N",
+        default_value_t = 100_000
+    )]
+    archive_max_entries: usize,
+
+    /// Directory with listing templates overriding the built-in theme
+    /// (listing.html, theme.css; minijinja syntax)
+    #[arg(long = "template-dir", value_name = "DIR")]
+    template_dir: Option<PathBuf>,
+
+    /// Also keep gallery thumbnails in this directory (created if missing), so they
+    /// survive restarts. Without it thumbnails are cached in memory only.
+    #[arg(long = "thumb-cache", value_name = "DIR")]
+    thumb_cache: Option<PathBuf>,
+
+    /// Serve everything under this URL path (e.g. /files) instead of /, for
+    /// mounting behind a reverse proxy that does not strip the prefix
+    #[arg(long = "route-prefix", value_name = "PATH")]
+    route_prefix: Option<String>,
+
+    /// Offer file checksums (sha256, blake3; comma-separated, default sha256): a listing
+    /// column, ?checksum=ALG on files and directories (manifest), and Repr-Digest headers.
+    /// Digests are computed on demand and cached by path, size and mtime.
+    #[arg(
+        long = "checksums",
+        value_name = "ALGS",
+        value_delimiter = ',',
+        num_args = 0..=1,
+        default_missing_value = "sha256"
+    )]
+    checksums: Option<Vec<String>>,
+
+    /// Enable signed share links (?id=&exp=&sig=) with the HMAC key in this file,
+    /// created on first use. Revocations and download counts go to FILE.state.
+    #[arg(long = "share-key", value_name = "FILE")]
+    share_key: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print a signed share link for a file or directory (needs --share-key)
+    Share(ShareArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ShareArgs {
+    /// File or directory to share, relative to the served directory
+    path: PathBuf,
+
+    /// How long the link works: 90s, 30m, 12h or 7d
+    #[arg(long = "expires", value_name = "DURATION", default_value = "24h", value_parser = parse_duration)]
+    expires: std::time::Duration,
+
+    /// Stop working after this many downloads
+    #[arg(long = "max-downloads", value_name = "N")]
+    max_downloads: Option<u64>,
+
+    /// Only accept the link from this client IP
+    #[arg(long = "ip", value_name = "ADDR")]
+    ip: Option<IpAddr>,
+
+    /// Start of the printed URL (default: from --interface, --port and --https)
+    #[arg(long = "base-url", value_name = "URL")]
+    base_url: Option<String>,
 }
 
 #[derive(Clone)]
 struct AppState {
     root: PathBuf,            // canonicalized
-    auth: Option<AuthConfig>, // parsed credentials
+    auth: Option<AuthConfig>, // --auth or --auth-file
+    acl: Option<Arc<Acl>>,
+    shares: Option<Arc<Shares>>,     // --share-key
+    sessions: Option<Arc<Sessions>>, // --login-form
+    https: bool,
     console: bool,
+    etag_hash: bool,
+    checksums: Arc<ChecksumCache>,
+    checksum_algs: Vec<checksum::Algorithm>, // empty unless --checksums
+    compress: bool,
+    precompressed: bool,
+    spa: Option<PathBuf>, // canonicalized fallback file
+    error_pages: ErrorPages,
+    clean_urls: bool,
+    index_files: Vec<String>,
+    archive_limits: archive::Limits,
+    theme: Arc<Theme>,
+    thumbnails: Arc<ThumbnailCache>,
+    lockout: Arc<Lockout>,
+    prefix: String, // "" or "/mount", percent-encoded, no trailing '/'
 }
 
 #[derive(Clone)]
-struct AuthConfig {
-    user: String,
-    pass: String,
+enum AuthConfig {
+    // --auth user:pass
+    Single { user: String, pass: String },
+    // --auth-file
+    File(Arc<Htpasswd>),
 }
 
 impl AuthConfig {
@@ -79,11 +278,31 @@ impl AuthConfig {
         if user.is_empty() || pass.is_empty() {
             return Err("auth user and pass must be non-empty");
         }
-        Ok(Self {
+        Ok(Self::Single {
             user: user.to_string(),
             pass: pass.to_string(),
         })
     }
+
+    async fn verify(&self, user: &str, pass: &str) -> bool {
+        match self {
+            // Both halves are always compared, so timing says nothing about
+            // which one was wrong.
+            AuthConfig::Single { user: u, pass: p } => {
+                let user_ok = lockout::constant_time_eq(user.as_bytes(), u.as_bytes());
+                let pass_ok = lockout::constant_time_eq(pass.as_bytes(), p.as_bytes());
+                user_ok & pass_ok
+            }
+            AuthConfig::File(file) => file.verify(user, pass).await,
+        }
+    }
+
+    async fn has_user(&self, user: &str) -> bool {
+        match self {
+            AuthConfig::Single { user: u, .. } => u == user,
+            AuthConfig::File(file) => file.has_user(user).await,
+        }
+    }
 }
 
 #[tokio::main]
@@ -99,44 +318,220 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
 
     let root = args
         .dir
+        .clone()
         .unwrap_or_else(|| std::env::current_dir().expect("failed to get current directory"))
         .canonicalize()
         .unwrap_or_else(|e| panic!("cannot canonicalize dir: {e}"));
 
-    let auth = match args.auth.as_deref() {
-        Some(s) => Some(AuthConfig::parse(s).unwrap_or_else(|e| panic!("{e}"))),
+    let auth = match (&args.auth, &args.auth_file) {
+        (Some(s), _) => Some(AuthConfig::parse(s).unwrap_or_else(|e| panic!("{e}"))),
+        (None, Some(path)) => Some(AuthConfig::File(Arc::new(Htpasswd::load(path)?))),
+        (None, None) => None,
+    };
+
+    let acl = match &args.acl {
+        Some(path) => Some(Arc::new(Acl::load(path, &root)?)),
+        None => None,
+    };
+
+    if args.login_form && auth.is_none() {
+        return Err("--login-form needs --auth or --auth-file".into());
+    }
+    let sessions = args
+        .login_form
+        .then(|| Arc::new(Sessions::new(args.session_ttl)));
+
+    let shares = match &args.share_key {
+        Some(path) => Some(Arc::new(Shares::load(path)?)),
+        None => None,
+    };
+    if let Some(Command::Share(cmd)) = &args.command {
+        let shares = shares.ok_or("`lantrix share` needs --share-key FILE")?;
+        return print_share_link(&args, cmd, &root, &shares);
+    }
+
+    let spa = match &args.spa {
+        Some(p) => {
+            let fallback = root
+                .join(p)
+                .canonicalize()
+                .map_err(|e| format!("cannot resolve --spa fallback {}: {e}", p.display()))?;
+            if !fallback.starts_with(&root) || !fallback.is_file() {
+                return Err("--spa fallback must be a file inside the served directory".into());
+            }
+            Some(fallback)
+        }
+        None => None,
+    };
+
+    let error_pages_dir = match &args.error_pages {
+        Some(p) => p
+            .canonicalize()
+            .map_err(|e| format!("cannot resolve --error-pages {}: {e}", p.display()))?,
+        None => root.clone(),
+    };
+
+    let index_files: Vec<String> = args
+        .index
+        .iter()
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .collect();
+    if index_files
+        .iter()
+        .any(|n| n.contains(['/', '\\']) || n == "." || n == "..")
+    {
+        return Err("--index entries must be plain file names".into());
+    }
+
+    let template_dir = match &args.template_dir {
+        Some(p) => Some(
+            p.canonicalize()
+                .map_err(|e| format!("cannot resolve --template-dir {}: {e}", p.display()))?,
+        ),
         None => None,
     };
+    let theme = Theme::load(template_dir.as_deref())?;
+
+    let thumb_cache = match &args.thumb_cache {
+        Some(p) => {
+            std::fs::create_dir_all(p)
+                .map_err(|e| format!("cannot create --thumb-cache {}: {e}", p.display()))?;
+            Some(p.canonicalize()?)
+        }
+        None => None,
+    };
+
+    let prefix = normalize_prefix(args.route_prefix.as_deref().unwrap_or(""))?;
+
+    let mut checksum_algs = Vec::new();
+    for name in args.checksums.iter().flatten() {
+        let alg = checksum::Algorithm::parse(name).ok_or_else(|| {
+            format!("unknown --checksums algorithm {name:?} (use sha256 or blake3)")
+        })?;
+        if !checksum_algs.contains(&alg) {
+            checksum_algs.push(alg);
+        }
+    }
 
     let addr: SocketAddr = format!("{}:{}", args.interface, args.port)
         .parse()
         .map_err(|_| "invalid interface/port")?;
 
     println!("Serving: {}", root.display());
+    match &auth {
+        Some(AuthConfig::File(file)) => {
+            println!(
+                "Auth: enabled ({} users from --auth-file)",
+                file.user_count()
+            )
+        }
+        Some(AuthConfig::Single { .. }) => println!("Auth: enabled"),
+        None => println!("Auth: disabled"),
+    }
+    if args.login_form {
+        println!("Login: form at {prefix}/__login");
+    }
+    if let (Some(acl), Some(path)) = (&acl, &args.acl) {
+        println!(
+            "ACL: {} sections from {}",
+            acl.section_count(),
+            path.display()
+        );
+        if auth.is_none() {
+            eprintln!("Warning: --acl without --auth or --auth-file; only `*` rules can apply");
+        }
+    }
     println!(
-        "Auth: {}",
-        if auth.is_some() { "enabled" } else { "disabled" }
+        "Compression: {}",
+        if args.compress { "enabled" } else { "disabled" }
     );
+    if let Some(dir) = &template_dir {
+        println!("Templates: {}", dir.display());
+    }
+    if !prefix.is_empty() {
+        println!("Route prefix: {prefix}/");
+    }
+    if let Some(fallback) = &spa {
+        println!("SPA fallback: {}", display_rel(&root, fallback));
+    }
     println!(
         "Console: {}",
-        if args.console { "enabled (/__console)" } else { "disabled" }
+        if args.console {
+            format!("enabled ({prefix}/__console)")
+        } else {
+            "disabled".to_string()
+        }
     );
 
     let state = Arc::new(AppState {
         root,
         auth,
+        acl,
+        shares,
+        sessions,
+        https: args.https,
         console: args.console,
+        etag_hash: args.etag_hash,
+        checksums: Arc::new(ChecksumCache::default()),
+        checksum_algs,
+        compress: args.compress,
+        precompressed: args.precompressed,
+        spa,
+        error_pages: ErrorPages {
+            dir: error_pages_dir,
+        },
+        clean_urls: args.clean_urls,
+        index_files,
+        archive_limits: archive::Limits {
+            max_bytes: args.archive_max_bytes,
+            max_entries: args.archive_max_entries,
+        },
+        theme: Arc::new(theme),
+        thumbnails: Arc::new(ThumbnailCache::new(thumb_cache)),
+        lockout: Arc::new(Lockout::default()),
+        prefix: prefix.clone(),
     });
 
     let mut app = Router::new()
-        .route("/", get(serve_root))
-        .route("/*path", get(serve_path));
+        .route("/", get(serve_root).head(serve_root).options(options_read))
+        .route(
+            "/*path",
+            get(serve_path).head(serve_path).options(options_read),
+        );
 
     if args.console {
         app = app
-            .route("/__console", get(console_page))
-            .route("/__console/api", post(console_api))
-            .route("/__console/upload", post(console_upload)); // <-- NEW
+            .route(
+                "/__console",
+                get(console_page).head(console_page).options(options_read),
+            )
+            .route("/__console/api", post(console_api).options(options_post))
+            .route(
+                "/__console/upload",
+                post(console_upload).options(options_post),
+            );
+    }
+
+    if args.login_form {
+        app = app
+            .route(
+                "/__login",
+                get(login_page)
+                    .head(login_page)
+                    .post(login_submit)
+                    .options(options_read_post),
+            )
+            .route("/__logout", post(logout).options(options_post));
+    }
+
+    // nest() matches "/files" but not "/files/", which is where the root
+    // listing lives, so that route is added explicitly.
+    if !prefix.is_empty() {
+        app = Router::new().nest(&prefix, app).route(
+            &format!("{prefix}/"),
+            get(serve_root).head(serve_root).options(options_read),
+        );
     }
 
     // IMPORTANT: use axum::Extension (layer type)
@@ -161,7 +556,7 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
         );
 
         axum_server::bind_rustls(addr, tls)
-            .serve(app.into_make_service())
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .await?;
 
         Ok(())
@@ -172,203 +567,2012 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
 
         println!("Listening on: http://{addr}");
 
-        let listener = tokio::net::TcpListener::bind(addr)
-            .await
-            .unwrap_or_else(|e| panic!("failed to bind {addr}: {e}"));
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|e| panic!("failed to bind {addr}: {e}"));
+
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+// `lantrix share PATH`: mint a link and print it, without starting a server.
+fn print_share_link(
+    args: &Args,
+    cmd: &ShareArgs,
+    root: &Path,
+    shares: &Shares,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let target = root
+        .join(&cmd.path)
+        .canonicalize()
+        .map_err(|e| format!("cannot resolve {}: {e}", cmd.path.display()))?;
+    if !target.starts_with(root) {
+        return Err("the shared path must be inside the served directory".into());
+    }
+    let prefix = normalize_prefix(args.route_prefix.as_deref().unwrap_or(""))?;
+    let base = match &cmd.base_url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => {
+            let scheme = if args.https { "https" } else { "http" };
+            match args.interface.parse::<IpAddr>() {
+                Ok(IpAddr::V6(ip)) => format!("{scheme}://[{ip}]:{}", args.port),
+                _ => format!("{scheme}://{}:{}", args.interface, args.port),
+            }
+        }
+    };
+
+    let token = shares.mint(
+        share_path(root, &target),
+        cmd.expires,
+        cmd.max_downloads,
+        cmd.ip,
+    );
+    println!(
+        "{}",
+        share_link(&base, &prefix, root, &target, target.is_dir(), &token)
+    );
+    eprintln!("id {}, {}", token.id, share_summary(&token));
+    Ok(())
+}
+
+fn share_link(
+    base: &str,
+    prefix: &str,
+    root: &Path,
+    target: &Path,
+    is_dir: bool,
+    token: &share::Token,
+) -> String {
+    let url = listing::url_for(prefix, root, target, is_dir);
+    format!("{base}{url}?{}", token.query())
+}
+
+fn share_summary(token: &share::Token) -> String {
+    let exp = std::time::UNIX_EPOCH + std::time::Duration::from_secs(token.exp);
+    let mut out = format!("expires {}", listing::format_rfc3339(exp));
+    if let Some(max) = token.max {
+        out.push_str(&format!(", at most {max} downloads"));
+    }
+    if let Some(ip) = token.ip {
+        out.push_str(&format!(", only from {ip}"));
+    }
+    out
+}
+
+// "30m", "12h", "7d", "90s" or plain seconds.
+fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    let s = s.trim();
+    let (num, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let n: u64 = num.parse().map_err(|_| format!("invalid duration {s:?}"))?;
+    let secs = match unit {
+        "s" => n,
+        "m" => n.saturating_mul(60),
+        "h" => n.saturating_mul(60 * 60),
+        "d" => n.saturating_mul(24 * 60 * 60),
+        _ => return Err(format!("invalid duration {s:?} (use s, m, h or d)")),
+    };
+    if secs == 0 {
+        return Err("duration must be positive".to_string());
+    }
+    Ok(std::time::Duration::from_secs(secs))
+}
+
+// "/files/" -> "/files", "" or "/" -> "". Segments are percent-encoded so the
+// prefix can be pasted into generated links as-is.
+fn normalize_prefix(raw: &str) -> Result<String, String> {
+    let trimmed = raw.trim().trim_matches('/');
+    if trimmed.is_empty() {
+        return Ok(String::new());
+    }
+    let mut prefix = String::new();
+    for seg in trimmed.split('/') {
+        if seg.is_empty() || seg == "." || seg == ".." || seg.contains(['?', '#', '*', ':']) {
+            return Err(format!("invalid --route-prefix {raw:?}"));
+        }
+        prefix.push('/');
+        prefix.push_str(&urlencoding::encode(seg));
+    }
+    Ok(prefix)
+}
+
+async fn generate_self_signed_tls_with_pem(
+    interface: &str,
+) -> Result<(RustlsConfig, String, String), Box<dyn std::error::Error>> {
+    use rcgen::{CertificateParams, DistinguishedName, DnType, SanType};
+
+    let mut params = CertificateParams::new(vec!["localhost".to_string()])?;
+
+    let mut dn = DistinguishedName::new();
+    dn.push(DnType::CommonName, "lantrix");
+    params.distinguished_name = dn;
+
+    params
+        .subject_alt_names
+        .push(SanType::IpAddress(IpAddr::from([127, 0, 0, 1])));
+
+    if let Ok(ip) = interface.parse::<IpAddr>() {
+        if !ip.is_unspecified() {
+            params.subject_alt_names.push(SanType::IpAddress(ip));
+        }
+    }
+
+    let key_pair = rcgen::KeyPair::generate()?;
+    let cert = params.self_signed(&key_pair)?;
+
+    let cert_pem = cert.pem();
+    let key_pem = key_pair.serialize_pem();
+
+    let tls = RustlsConfig::from_pem(
+        cert_pem.clone().into_bytes(),
+        key_pem.clone().into_bytes(),
+    )
+    .await?;
+
+    Ok((tls, cert_pem, key_pem))
+}
+
+async fn serve_root(
+    Ext(state): Ext<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+) -> Response {
+    let resp = serve_rel_path(&state, &headers, &method, client.ip(), &uri, "").await;
+    let resp = login_redirect(&state, &headers, &uri, resp);
+    let resp = state.error_pages.render(&headers, "", resp).await;
+    finish_response(&method, resp)
+}
+
+// The path is taken from the (prefix-stripped) request URI rather than a Path
+// extractor, which would reject non-UTF-8 names before we get to decode them.
+async fn serve_path(
+    Ext(state): Ext<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    method: Method,
+    OriginalUri(original): OriginalUri,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let rel = uri.path().trim_start_matches('/');
+    let resp = serve_rel_path(&state, &headers, &method, client.ip(), &original, rel).await;
+    let resp = login_redirect(&state, &headers, &original, resp);
+    let shown = String::from_utf8_lossy(&urlencoding::decode_binary(rel.as_bytes())).into_owned();
+    let resp = state.error_pages.render(&headers, &shown, resp).await;
+    finish_response(&method, resp)
+}
+
+async fn options_read() -> Response {
+    allow_response(ALLOW_READ)
+}
+
+async fn options_post() -> Response {
+    allow_response(ALLOW_POST)
+}
+
+async fn options_read_post() -> Response {
+    allow_response(ALLOW_READ_POST)
+}
+
+fn allow_response(allow: &'static str) -> Response {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ALLOW, allow)
+        .body(Body::empty())
+        .unwrap()
+}
+
+// HEAD gets exactly the headers GET would produce, but the body is dropped
+// before it is ever polled, so file contents are never read.
+fn finish_response(method: &Method, resp: Response) -> Response {
+    if method != Method::HEAD {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    if !parts.headers.contains_key(header::CONTENT_LENGTH) {
+        if let Some(n) = body.size_hint().exact() {
+            parts
+                .headers
+                .insert(header::CONTENT_LENGTH, HeaderValue::from(n));
+        }
+    }
+    Response::from_parts(parts, Body::empty())
+}
+
+// `uri` is the full request URI (including any --route-prefix), used for
+// redirects; `rel` is the still percent-encoded path below the root. A share
+// link or cookie stands in for credentials, but only for requests without
+// any: signed-in users keep their own access.
+async fn serve_rel_path(
+    state: &AppState,
+    headers: &HeaderMap,
+    method: &Method,
+    client: IpAddr,
+    uri: &Uri,
+    rel: &str,
+) -> Response {
+    let Some(rel_path) = listing::decode_path(rel) else {
+        return error(StatusCode::BAD_REQUEST, "Bad URL encoding");
+    };
+    let query = Query::<HashMap<String, String>>::try_from_uri(uri)
+        .map(|q| q.0)
+        .unwrap_or_default();
+
+    let identified = match identify(state, headers, client).await {
+        Ok(a) => a,
+        Err(resp) => return resp,
+    };
+    let grant = if identified.user().is_some() {
+        None
+    } else {
+        let download = is_download(state, method, &rel_path, &query).await;
+        match share_grant(state, headers, &query, &rel_path, client, download).await {
+            Ok(g) => g,
+            Err((status, message)) => return error(status, message),
+        }
+    };
+    let access = match &grant {
+        Some(g) => g.access.clone(),
+        None => identified,
+    };
+
+    let resp = serve_with_access(state, headers, &access, uri, &rel_path, &query).await;
+    match grant {
+        Some(g) => finish_share(state, &g, resp),
+        None => resp,
+    }
+}
+
+async fn serve_with_access(
+    state: &AppState,
+    headers: &HeaderMap,
+    access: &Access,
+    uri: &Uri,
+    rel_path: &Path,
+    query: &HashMap<String, String>,
+) -> Response {
+    let decoded = rel_path.to_string_lossy().into_owned();
+
+    // Checked on the requested path before touching the disk, so existence
+    // isn't revealed, and again below on where it resolves to.
+    let candidate = state.root.join(rel_path);
+    if !access
+        .perms(&candidate)
+        .intersects(Perms::READ | Perms::LIST)
+    {
+        return denied(state, access);
+    }
+
+    if !state.console && (decoded.starts_with("__console") || decoded.starts_with("/__console")) {
+        return error(StatusCode::NOT_FOUND, "Not found");
+    }
+
+    let meta = match tokio::fs::metadata(&candidate).await {
+        Ok(m) => m,
+        Err(_) => {
+            if state.clean_urls {
+                let trimmed = decoded.trim_end_matches('/');
+                let html = state.root.join(format!("{trimmed}.html"));
+                if !trimmed.is_empty()
+                    && tokio::fs::metadata(&html).await.is_ok_and(|m| m.is_file())
+                {
+                    if decoded.ends_with('/') {
+                        return redirect(uri, local_path(uri).trim_end_matches('/'));
+                    }
+                    return serve_resolved(state, headers, access, &html).await;
+                }
+            }
+            if let Some(fallback) = &state.spa {
+                if is_spa_navigation(headers, &decoded) {
+                    if !access.allows(fallback, Perms::READ) {
+                        return denied(state, access);
+                    }
+                    return serve_file(state, fallback, headers, access).await;
+                }
+            }
+            return error(StatusCode::NOT_FOUND, "Not found");
+        }
+    };
+
+    // Directory URLs always end in '/', otherwise relative links in listings
+    // and index pages resolve against the parent. This includes the root when
+    // it is mounted at a --route-prefix.
+    if meta.is_dir() && !uri.path().ends_with('/') {
+        return redirect(uri, &format!("{}/", local_path(uri)));
+    }
+
+    if state.clean_urls && meta.is_file() {
+        if let Some(resp) = clean_url_redirect(state, uri, &decoded).await {
+            return resp;
+        }
+    }
+
+    let canon = match tokio::fs::canonicalize(&candidate).await {
+        Ok(p) => p,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Forbidden"),
+    };
+
+    if !canon.starts_with(&state.root) {
+        return error(StatusCode::FORBIDDEN, "Forbidden");
+    }
+
+    let need = if meta.is_dir() {
+        Perms::LIST
+    } else {
+        Perms::READ
+    };
+    if !access.allows(&canon, need) {
+        return denied(state, access);
+    }
+
+    if let Some(alg) = query.get("checksum") {
+        let alg = match checksum_alg(state, alg) {
+            Ok(alg) => alg,
+            Err((status, message)) => return error(status, message),
+        };
+        if meta.is_dir() {
+            return checksum_manifest(state, &canon, alg, access).await;
+        }
+        return serve_checksum(state, &canon, alg, headers).await;
+    }
+
+    if meta.is_dir() {
+        if let Some(format) = query.get("archive") {
+            return archive_dir(state, &canon, format, access).await;
+        }
+        if query.get("view").map(String::as_str) == Some("gallery") {
+            return gallery_dir(state, &canon, query, headers, access).await;
+        }
+        if let Some(q) = query.get("q").filter(|q| !q.trim().is_empty()) {
+            return search_dir(state, &canon, q.trim(), query, headers, access).await;
+        }
+        if let Some(index) = find_index_file(&canon, &state.index_files).await {
+            return serve_resolved(state, headers, access, &index).await;
+        }
+        return list_dir(state, &canon, query, headers, access).await;
+    }
+
+    let mime = mime_guess::from_path(&canon).first_or_octet_stream();
+    if gallery::is_image(mime.essence_str()) {
+        if let Some(size) = query.get("thumb") {
+            return serve_thumbnail(state, &canon, gallery::thumb_size(size), headers).await;
+        }
+        if query.contains_key("exif") {
+            return serve_image_info(&canon).await;
+        }
+    }
+
+    if markdown::is_markdown(&canon) {
+        return serve_markdown(state, &canon, query, headers, access).await;
+    }
+
+    serve_file(state, &canon, headers, access).await
+}
+
+// Markdown files are rendered to HTML with ?render=1, served raw with
+// ?render=0, and otherwise negotiated: clients preferring text/html over
+// text/markdown (browsers) get the rendered page.
+async fn serve_markdown(
+    state: &AppState,
+    path: &Path,
+    query: &HashMap<String, String>,
+    headers: &HeaderMap,
+    access: &Access,
+) -> Response {
+    let negotiated = !query.contains_key("render");
+    let render = match query.get("render").map(String::as_str) {
+        Some("1" | "true" | "yes") => true,
+        Some(_) => false,
+        None => accept_q(headers, "text/html") > accept_q(headers, "text/markdown"),
+    };
+
+    let meta = tokio::fs::metadata(path).await.ok();
+    let too_large = meta
+        .as_ref()
+        .is_none_or(|m| m.len() > markdown::MAX_RENDER_BYTES);
+    if !render || too_large {
+        let mut resp = serve_file(state, path, headers, access).await;
+        if negotiated {
+            append_vary(&mut resp, "accept");
+        }
+        return resp;
+    }
+
+    let source = match tokio::fs::read(path).await {
+        Ok(b) => String::from_utf8_lossy(&b).into_owned(),
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read file"),
+    };
+    let ctx = MarkdownContext::new(&state.prefix, &state.root, path, markdown::to_html(&source));
+    let html = match state.theme.render_markdown(&ctx) {
+        Ok(html) => html,
+        Err(e) => {
+            eprintln!("markdown template error: {e:#}");
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "Template error");
+        }
+    };
+
+    generated_response(
+        state,
+        headers,
+        "text/html; charset=utf-8",
+        html.into_bytes(),
+        meta.and_then(|m| m.modified().ok()),
+    )
+}
+
+// ?q=pattern[&match=substring|glob|regex][&limit=N]: names matching below
+// `dir`, as a listing page or (?format=json / Accept) JSON.
+async fn search_dir(
+    state: &AppState,
+    dir: &Path,
+    pattern: &str,
+    query: &HashMap<String, String>,
+    headers: &HeaderMap,
+    access: &Access,
+) -> Response {
+    let (matcher, mode) =
+        match search::Matcher::from_query(pattern, query.get("match").map(String::as_str)) {
+            Ok(m) => m,
+            Err(e) => {
+                eprintln!("search pattern {pattern:?}: {e}");
+                return error(StatusCode::BAD_REQUEST, "Invalid search pattern");
+            }
+        };
+    let sort = Sort::from_query(query);
+    let limit = search::limit_from_query(query);
+
+    let results = match search::search(&state.root, dir, &matcher, limit, sort, access).await {
+        Ok(r) => r,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read directory"),
+    };
+
+    if query.get("format").map(String::as_str) == Some("json") || prefers_json(headers) {
+        let dir_url = listing::url_for(&state.prefix, &state.root, dir, true);
+        let body = search::JsonResults {
+            path: display_rel(&state.root, dir),
+            query: pattern.to_string(),
+            mode: mode.as_str(),
+            entries: results
+                .hits
+                .iter()
+                .map(|w| listing::JsonEntry::new(&dir_url, w))
+                .collect(),
+            truncated: results.truncated,
+            timed_out: results.timed_out,
+        };
+        let json = serde_json::to_vec(&body).unwrap_or_default();
+        return generated_response(state, headers, "application/json", json, None);
+    }
+
+    let mut ctx = ListingContext::new(&state.prefix, &state.root, dir, &[], sort, state.console);
+    ctx.show_search(pattern, mode, &results);
+    ctx.account = account_link(state, access);
+    let html = match state.theme.render_listing(&ctx) {
+        Ok(html) => html,
+        Err(e) => {
+            eprintln!("listing template error: {e:#}");
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "Template error");
+        }
+    };
+
+    generated_response(
+        state,
+        headers,
+        "text/html; charset=utf-8",
+        html.into_bytes(),
+        None,
+    )
+}
+
+async fn gallery_dir(
+    state: &AppState,
+    dir: &Path,
+    query: &HashMap<String, String>,
+    headers: &HeaderMap,
+    access: &Access,
+) -> Response {
+    let (mut entries, latest) = match listing::read_entries(dir, access).await {
+        Ok(r) => r,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read directory"),
+    };
+    listing::sort_entries(&mut entries, Sort::from_query(query));
+
+    let ctx = GalleryContext::new(
+        &state.prefix,
+        &state.root,
+        dir,
+        &entries,
+        gallery::DEFAULT_THUMB_SIZE,
+    );
+    let html = match state.theme.render_gallery(&ctx) {
+        Ok(html) => html,
+        Err(e) => {
+            eprintln!("gallery template error: {e:#}");
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "Template error");
+        }
+    };
+
+    generated_response(
+        state,
+        headers,
+        "text/html; charset=utf-8",
+        html.into_bytes(),
+        latest,
+    )
+}
+
+// ?thumb[=size] on an image. Validators derive from the source file, so
+// browsers revalidate thumbnails without them being regenerated.
+async fn serve_thumbnail(
+    state: &AppState,
+    path: &Path,
+    size: u32,
+    headers: &HeaderMap,
+) -> Response {
+    let meta = match tokio::fs::metadata(path).await {
+        Ok(m) => m,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read file"),
+    };
+    let validators = Validators::new(conditional::metadata_etag(&meta), meta.modified().ok())
+        .for_encoding(&format!("thumb{size}"));
+    if let Some(status) = conditional::evaluate(headers, &validators) {
+        return precondition_response(status, &validators);
+    }
+
+    let thumb = match state.thumbnails.get(path, &meta, size).await {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("thumbnail {}: {e}", path.display());
+            return error(StatusCode::UNPROCESSABLE_ENTITY, "Cannot create thumbnail");
+        }
+    };
+
+    let mut resp = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, thumb.content_type)
+        .body(Body::from(thumb.bytes))
+        .unwrap();
+    validators.apply(resp.headers_mut());
+    resp
+}
+
+// ?exif=1 on an image: dimensions and selected EXIF fields as JSON.
+async fn serve_image_info(path: &Path) -> Response {
+    match gallery::image_info(path).await {
+        Ok(info) => Json(info).into_response(),
+        Err(_) => error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Cannot read image metadata",
+        ),
+    }
+}
+
+// Validates ?checksum=ALG against --checksums.
+fn checksum_alg(
+    state: &AppState,
+    value: &str,
+) -> Result<checksum::Algorithm, (StatusCode, &'static str)> {
+    if state.checksum_algs.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "Checksums are not enabled"));
+    }
+    checksum::Algorithm::parse(value)
+        .filter(|alg| state.checksum_algs.contains(alg))
+        .ok_or((StatusCode::BAD_REQUEST, "Unknown checksum algorithm"))
+}
+
+// ?checksum=ALG on a file: one sha256sum/b3sum style line.
+async fn serve_checksum(
+    state: &AppState,
+    path: &Path,
+    alg: checksum::Algorithm,
+    headers: &HeaderMap,
+) -> Response {
+    let meta = match tokio::fs::metadata(path).await {
+        Ok(m) => m,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read file"),
+    };
+    let validators = Validators::new(conditional::metadata_etag(&meta), meta.modified().ok())
+        .for_encoding(alg.as_str());
+    if let Some(status) = conditional::evaluate(headers, &validators) {
+        return precondition_response(status, &validators);
+    }
+
+    let digest = match state.checksums.digest(path, &meta, alg).await {
+        Ok(d) => d,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read file"),
+    };
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut resp = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(checksum::manifest_line(&digest, &name)))
+        .unwrap();
+    validators.apply(resp.headers_mut());
+    resp
+}
+
+// ?checksum=ALG on a directory: a SHA256SUMS / B3SUMS manifest of every file
+// below it, paths relative to the directory. Files are hashed as the body
+// streams; ones that can't be read (or resolve outside the root, or somewhere
+// the client may not read) are skipped.
+async fn checksum_manifest(
+    state: &AppState,
+    dir: &Path,
+    alg: checksum::Algorithm,
+    access: &Access,
+) -> Response {
+    let (entries, truncated, _) = match listing::walk(
+        &state.root,
+        dir,
+        listing::MAX_RECURSIVE_DEPTH,
+        Sort::default(),
+        access,
+    )
+    .await
+    {
+        Ok(r) => r,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read directory"),
+    };
+    if truncated {
+        return error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Directory has more than {} entries, too many for a manifest",
+                listing::MAX_RECURSIVE_ENTRIES
+            ),
+        );
+    }
+
+    let root = state.root.clone();
+    let cache = state.checksums.clone();
+    let access = access.clone();
+    let files = entries.into_iter().filter(|w| !w.entry.is_dir);
+    let lines = stream::iter(files).filter_map(move |w| {
+        let root = root.clone();
+        let cache = cache.clone();
+        let access = access.clone();
+        async move {
+            let canon = tokio::fs::canonicalize(&w.path).await.ok()?;
+            if !canon.starts_with(&root) || !access.allows(&canon, Perms::READ) {
+                return None;
+            }
+            let meta = tokio::fs::metadata(&canon).await.ok()?;
+            let digest = cache.digest(&canon, &meta, alg).await.ok()?;
+            let line = checksum::manifest_line(&digest, &w.rel);
+            Some(Ok::<_, std::io::Error>(Bytes::from(line)))
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("inline; filename=\"{}\"", alg.manifest_name()),
+        )
+        .body(Body::from_stream(lines))
+        .unwrap()
+}
+
+fn append_vary(resp: &mut Response, value: &str) {
+    let existing = resp
+        .headers()
+        .get(header::VARY)
+        .and_then(|v| v.to_str().ok());
+    let vary = match existing {
+        Some(existing) => format!("{existing}, {value}"),
+        None => value.to_string(),
+    };
+    if let Ok(v) = HeaderValue::from_str(&vary) {
+        resp.headers_mut().insert(header::VARY, v);
+    }
+}
+
+async fn archive_dir(state: &AppState, dir: &Path, format: &str, access: &Access) -> Response {
+    let Some(format) = archive::Format::from_query(format) else {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "Unknown archive format (use zip or tar.gz)",
+        );
+    };
+
+    let top = dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| "archive".to_string());
+
+    let plan = match archive::plan(&state.root, dir, &top, state.archive_limits, access).await {
+        Ok(p) => p,
+        Err(archive::PlanError::TooLarge) => {
+            return error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "Archive exceeds the size limit of {} bytes (--archive-max-bytes)",
+                    state.archive_limits.max_bytes
+                ),
+            )
+        }
+        Err(archive::PlanError::TooManyEntries) => {
+            return error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "Archive exceeds the limit of {} entries (--archive-max-entries)",
+                    state.archive_limits.max_entries
+                ),
+            )
+        }
+        Err(archive::PlanError::Unreadable) => {
+            return error(StatusCode::FORBIDDEN, "Cannot read directory")
+        }
+    };
+
+    let filename = format!("{top}.{}", format.extension());
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(header::CONTENT_DISPOSITION, content_disposition(&filename))
+        .body(archive::stream_body(format, plan))
+        .unwrap()
+}
+
+// attachment; filename="ascii fallback"; filename*=UTF-8''percent-encoded
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!(
+        "attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+        urlencoding::encode(filename)
+    )
+}
+
+// Serve a file found indirectly (index file, clean-URL resolution) with the
+// usual containment and permission checks, since it may be a symlink.
+async fn serve_resolved(
+    state: &AppState,
+    headers: &HeaderMap,
+    access: &Access,
+    path: &Path,
+) -> Response {
+    match tokio::fs::canonicalize(path).await {
+        Ok(canon) if !canon.starts_with(&state.root) => error(StatusCode::FORBIDDEN, "Forbidden"),
+        Ok(canon) if !access.allows(&canon, Perms::READ) => denied(state, access),
+        Ok(canon) => serve_file(state, &canon, headers, access).await,
+        Err(_) => error(StatusCode::FORBIDDEN, "Forbidden"),
+    }
+}
+
+// Canonical locations under --clean-urls: /a.html -> /a (unless /a is taken by
+// something else) and /d/index.html -> /d/.
+async fn clean_url_redirect(state: &AppState, uri: &Uri, decoded: &str) -> Option<Response> {
+    let raw = local_path(uri);
+    let stem = raw.strip_suffix(".html")?;
+    if let Some(dir) = stem.strip_suffix("/index") {
+        return Some(redirect(uri, &format!("{dir}/")));
+    }
+    let decoded_stem = decoded.strip_suffix(".html")?;
+    if tokio::fs::metadata(state.root.join(decoded_stem))
+        .await
+        .is_ok()
+    {
+        return None;
+    }
+    Some(redirect(uri, stem))
+}
+
+// The request path with any run of leading slashes collapsed to one, for
+// building redirects: "//host/x" in a Location is another site.
+fn local_path(uri: &Uri) -> String {
+    format!("/{}", uri.path().trim_start_matches('/'))
+}
+
+fn redirect(uri: &Uri, path: &str) -> Response {
+    let path = if path.is_empty() { "/" } else { path };
+    let location = match uri.query() {
+        Some(q) => format!("{path}?{q}"),
+        None => path.to_string(),
+    };
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+// Client-side routes look like browser navigations to extension-less paths;
+// anything with an extension is a missing asset and should still 404.
+fn is_spa_navigation(headers: &HeaderMap, rel: &str) -> bool {
+    let accepts_html = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|a| a.contains("text/html"));
+    let last = rel.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    accepts_html && !last.contains('.')
+}
+
+// Who is asking and what they may do. Requests without credentials (Basic
+// Auth, or else a --login-form session cookie) are anonymous, which without
+// --acl may do nothing when auth is on; wrong credentials are refused
+// outright. Only requests that actually present credentials count as failed
+// attempts, so a browser's first unauthenticated request doesn't.
+async fn identify(
+    state: &AppState,
+    headers: &HeaderMap,
+    client: IpAddr,
+) -> Result<Access, Response> {
+    let user = match (&state.auth, basic_credentials(headers)) {
+        (Some(cfg), Some((user, pass))) => {
+            if let Err(wait) = state.lockout.check(client, &user) {
+                return Err(too_many_attempts(wait));
+            }
+            if !cfg.verify(&user, &pass).await {
+                state.lockout.record_failure(client, &user);
+                return Err(unauthorized(true));
+            }
+            state.lockout.record_success(client, &user);
+            Some(user)
+        }
+        (Some(_), None) => session(state, headers).await.map(|(_, s)| s.user),
+        _ => None,
+    };
+    let fallback = if state.auth.is_none() || user.is_some() {
+        Perms::ALL
+    } else {
+        Perms::NONE
+    };
+    Ok(Access::new(state.acl.clone(), user, fallback))
+}
+
+// Anonymous clients are asked to log in; logged-in ones just lack permission.
+// With --login-form they sent no Basic credentials, so there's no challenge
+// to make browsers pop up their own dialog over the form.
+fn denied(state: &AppState, access: &Access) -> Response {
+    if state.auth.is_some() && access.user().is_none() {
+        return unauthorized(state.sessions.is_none());
+    }
+    error(StatusCode::FORBIDDEN, "Forbidden")
+}
+
+// A share link that let a request in, and what it allows.
+struct ShareGrant {
+    token: share::Token,
+    access: Access,
+    is_dir: bool,
+    from_query: bool,
+    // A download was counted against the link's limit up front.
+    counted: bool,
+}
+
+// Downloads count against a link's download limit: GET (not HEAD) of a file,
+// but not for thumbnails, EXIF or digests, and of a directory as an archive
+// or checksum manifest.
+async fn is_download(
+    state: &AppState,
+    method: &Method,
+    rel_path: &Path,
+    query: &HashMap<String, String>,
+) -> bool {
+    if method != Method::GET {
+        return false;
+    }
+    let Ok(meta) = tokio::fs::metadata(state.root.join(rel_path)).await else {
+        return false;
+    };
+    if meta.is_dir() {
+        return query.contains_key("archive") || query.contains_key("checksum");
+    }
+    !["thumb", "exif", "checksum"]
+        .iter()
+        .any(|k| query.contains_key(*k))
+}
+
+// ?sig= links are checked strictly: a bad or used-up link is an error. Share
+// cookies that don't cover the path (or no longer work) are just ignored, so
+// the request falls back to normal auth. For a `download`, the link's count
+// is taken before serving (see finish_share).
+async fn share_grant(
+    state: &AppState,
+    headers: &HeaderMap,
+    query: &HashMap<String, String>,
+    rel_path: &Path,
+    client: IpAddr,
+    download: bool,
+) -> Result<Option<ShareGrant>, (StatusCode, &'static str)> {
+    let Some(shares) = &state.shares else {
+        return Ok(None);
+    };
+    let path = share_path(&state.root, &state.root.join(rel_path));
+
+    if query.contains_key("sig") {
+        let token = share::Token::from_query(&path, query)
+            .ok_or((StatusCode::FORBIDDEN, "Invalid share link"))?;
+        shares.check(&token, client, download)?;
+        return match share_scope(state, token, true, download).await {
+            Some(grant) => Ok(Some(grant)),
+            None => Err((StatusCode::NOT_FOUND, "Not found")),
+        };
+    }
+
+    for value in cookie_values(headers, share::COOKIE) {
+        let Some(token) = share::Token::from_cookie(value) else {
+            continue;
+        };
+        if !token.covers(&path) || shares.check(&token, client, false).is_err() {
+            continue;
+        }
+        if let Some(mut grant) = share_scope(state, token, false, false).await {
+            if grant.is_dir {
+                if download {
+                    shares.check(&grant.token, client, true)?;
+                    grant.counted = true;
+                }
+                return Ok(Some(grant));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Resolves what the token names; the grant covers that, on disk. A download
+// already `counted` is given back if the target is gone.
+async fn share_scope(
+    state: &AppState,
+    token: share::Token,
+    from_query: bool,
+    counted: bool,
+) -> Option<ShareGrant> {
+    let resolved = match listing::decode_path(token.path.trim_start_matches('/')) {
+        Some(rel) => tokio::fs::canonicalize(state.root.join(rel)).await.ok(),
+        None => None,
+    };
+    let meta = match &resolved {
+        Some(scope) if scope.starts_with(&state.root) => tokio::fs::metadata(scope).await.ok(),
+        _ => None,
+    };
+    let (Some(scope), Some(meta)) = (resolved, meta) else {
+        if counted {
+            if let Some(shares) = &state.shares {
+                shares.release(&token);
+            }
+        }
+        return None;
+    };
+    let is_dir = meta.is_dir();
+    let perms = if is_dir {
+        Perms::READ | Perms::LIST
+    } else {
+        Perms::READ
+    };
+    Some(ShareGrant {
+        token,
+        access: Access::share(scope, perms),
+        is_dir,
+        from_query,
+        counted,
+    })
+}
+
+// What share links sign: the percent-encoded path below the root, without a
+// trailing '/' ("/" for the root itself).
+fn share_path(root: &Path, path: &Path) -> String {
+    let url = listing::url_for("", root, path, false);
+    match url.trim_end_matches('/') {
+        "" => "/".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+// Opening a directory link hands out the token as a cookie for that
+// directory. A download counted before serving is given back unless the file
+// was actually sent (200, or 206 for any range).
+fn finish_share(state: &AppState, grant: &ShareGrant, mut resp: Response) -> Response {
+    let ok = resp.status().is_success();
+    if ok && grant.is_dir && grant.from_query {
+        let cookie = format!(
+            "{}={}; Path={}{}/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            share::COOKIE,
+            grant.token.to_cookie(),
+            state.prefix,
+            grant.token.path.trim_end_matches('/'),
+            grant.token.seconds_left(),
+            if state.https { "; Secure" } else { "" }
+        );
+        if let Ok(v) = HeaderValue::from_str(&cookie) {
+            resp.headers_mut().append(header::SET_COOKIE, v);
+        }
+    }
+
+    let sent = matches!(resp.status(), StatusCode::OK | StatusCode::PARTIAL_CONTENT);
+    if grant.counted && !sent {
+        if let Some(shares) = &state.shares {
+            shares.release(&grant.token);
+        }
+    }
+    resp
+}
+
+// The --login-form session the request's cookie belongs to, with its id.
+// Sessions of users since removed from the --auth-file are dropped.
+async fn session(state: &AppState, headers: &HeaderMap) -> Option<(String, session::Session)> {
+    let sessions = state.sessions.as_ref()?;
+    let (id, s) = cookie_values(headers, session::COOKIE)
+        .find_map(|id| sessions.get(id).map(|s| (id.to_string(), s)))?;
+    if !state.auth.as_ref()?.has_user(&s.user).await {
+        sessions.remove(&id);
+        return None;
+    }
+    Some((id, s))
+}
+
+// Requests authenticated by a session cookie must carry the session's CSRF
+// token (header, or `csrf` form field); with Basic Auth or no session there
+// is nothing for another site to ride on.
+async fn csrf_ok(state: &AppState, headers: &HeaderMap, form_token: Option<&str>) -> bool {
+    if basic_credentials(headers).is_some() {
+        return true;
+    }
+    let Some((_, s)) = session(state, headers).await else {
+        return true;
+    };
+    let sent = headers
+        .get(session::CSRF_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .or(form_token)
+        .unwrap_or("");
+    lockout::constant_time_eq(sent.as_bytes(), s.csrf.as_bytes())
+}
+
+fn session_cookie(state: &AppState, id: &str, max_age: u64) -> String {
+    format!(
+        "{}={id}; Path={}/; Max-Age={max_age}; HttpOnly; SameSite=Lax{}",
+        session::COOKIE,
+        state.prefix,
+        if state.https { "; Secure" } else { "" }
+    )
+}
+
+// With --login-form, browsers that would get a Basic Auth challenge are sent
+// to the login page instead, which brings them back afterwards.
+fn login_redirect(state: &AppState, headers: &HeaderMap, uri: &Uri, resp: Response) -> Response {
+    let wants_html = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|a| a.contains("text/html"));
+    if state.sessions.is_none() || resp.status() != StatusCode::UNAUTHORIZED || !wants_html {
+        return resp;
+    }
+    let next = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let location = format!(
+        "{}/__login?next={}",
+        state.prefix,
+        urlencoding::encode(next)
+    );
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+// "Signed in as ..." in listing footers, linking to the sign-out page.
+fn account_link(state: &AppState, access: &Access) -> Option<theme::Link> {
+    state.sessions.as_ref()?;
+    Some(theme::Link {
+        name: access.user()?.to_string(),
+        url: format!("{}/__login", state.prefix),
+    })
+}
+
+// Only local paths under the prefix are followed after login, so the form
+// can't be used to bounce people to another site. Browsers drop tabs and
+// newlines and read `\` as `/`, so "/\t/evil.example" would still leave.
+fn safe_next(state: &AppState, next: Option<&str>) -> String {
+    safe_next_path(&state.prefix, next)
+}
+
+fn safe_next_path(prefix: &str, next: Option<&str>) -> String {
+    let home = format!("{prefix}/");
+    match next {
+        Some(n)
+            if n.starts_with(&home)
+                && !n.starts_with("//")
+                && !n.chars().any(|c| c == '\\' || c.is_whitespace() || c.is_control()) =>
+        {
+            n.to_string()
+        }
+        _ => home,
+    }
+}
+
+#[derive(Deserialize)]
+struct NextQuery {
+    next: Option<String>,
+}
+
+async fn login_page(
+    Ext(state): Ext<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(q): Query<NextQuery>,
+) -> Response {
+    let mut ctx = LoginContext::new(&state.prefix, &safe_next(&state, q.next.as_deref()));
+    if let Some((_, s)) = session(&state, &headers).await {
+        ctx.user = Some(s.user);
+        ctx.csrf = s.csrf;
+    }
+    render_login(&state, &ctx, StatusCode::OK)
+}
+
+fn render_login(state: &AppState, ctx: &LoginContext, status: StatusCode) -> Response {
+    match state.theme.render_login(ctx) {
+        Ok(html) => {
+            let mut resp = (status, Html(html)).into_response();
+            resp.headers_mut()
+                .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+            resp
+        }
+        Err(e) => {
+            eprintln!("login template error: {e:#}");
+            error(StatusCode::INTERNAL_SERVER_ERROR, "Template error")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    user: String,
+    pass: String,
+    next: Option<String>,
+}
+
+// Same checks and lockout as Basic Auth; failures re-show the form.
+async fn login_submit(
+    Ext(state): Ext<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    let (Some(cfg), Some(sessions)) = (&state.auth, &state.sessions) else {
+        return error(StatusCode::NOT_FOUND, "Not found");
+    };
+    let next = safe_next(&state, form.next.as_deref());
+    let mut ctx = LoginContext::new(&state.prefix, &next);
+    ctx.name = form.user.clone();
+
+    let client = client.ip();
+    if let Err(wait) = state.lockout.check(client, &form.user) {
+        ctx.error = Some(format!(
+            "Too many failed attempts. Try again in {} s.",
+            wait.as_secs() + 1
+        ));
+        let mut resp = render_login(&state, &ctx, StatusCode::TOO_MANY_REQUESTS);
+        resp.headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(wait.as_secs() + 1));
+        return resp;
+    }
+    if !cfg.verify(&form.user, &form.pass).await {
+        state.lockout.record_failure(client, &form.user);
+        ctx.error = Some("Wrong user name or password.".to_string());
+        return render_login(&state, &ctx, StatusCode::UNAUTHORIZED);
+    }
+    state.lockout.record_success(client, &form.user);
+
+    let id = sessions.create(&form.user);
+    let cookie = session_cookie(&state, &id, sessions.ttl().as_secs());
+    let mut resp = Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, next)
+        .body(Body::empty())
+        .unwrap();
+    if let Ok(v) = HeaderValue::from_str(&cookie) {
+        resp.headers_mut().insert(header::SET_COOKIE, v);
+    }
+    resp
+}
+
+#[derive(Deserialize)]
+struct LogoutForm {
+    csrf: Option<String>,
+}
 
-        axum::serve(listener, app).await?;
-        Ok(())
+async fn logout(
+    Ext(state): Ext<Arc<AppState>>,
+    headers: HeaderMap,
+    Form(form): Form<LogoutForm>,
+) -> Response {
+    if !csrf_ok(&state, &headers, form.csrf.as_deref()).await {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid CSRF token");
+    }
+    if let (Some(sessions), Some((id, _))) = (&state.sessions, session(&state, &headers).await) {
+        sessions.remove(&id);
     }
+    let mut resp = Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, format!("{}/__login", state.prefix))
+        .body(Body::empty())
+        .unwrap();
+    if let Ok(v) = HeaderValue::from_str(&session_cookie(&state, "", 0)) {
+        resp.headers_mut().insert(header::SET_COOKIE, v);
+    }
+    resp
 }
 
-async fn generate_self_signed_tls_with_pem(
-    interface: &str,
-) -> Result<(RustlsConfig, String, String), Box<dyn std::error::Error>> {
-    use rcgen::{CertificateParams, DistinguishedName, DnType, SanType};
-
-    let mut params = CertificateParams::new(vec!["localhost".to_string()])?;
+// Values of every `name` cookie sent with the request.
+fn cookie_values<'a>(headers: &'a HeaderMap, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(move |c| {
+            let (k, v) = c.trim().split_once('=')?;
+            (k == name).then_some(v)
+        })
+}
 
-    let mut dn = DistinguishedName::new();
-    dn.push(DnType::CommonName, "lantrix");
-    params.distinguished_name = dn;
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let s = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let b64 = s.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(B64.decode(b64).ok()?).ok()?;
 
-    params
-        .subject_alt_names
-        .push(SanType::IpAddress(IpAddr::from([127, 0, 0, 1])));
+    // The password may contain ':', the user name may not (RFC 7617).
+    let (user, pass) = decoded.split_once(':')?;
+    Some((user.to_string(), pass.to_string()))
+}
 
-    if let Ok(ip) = interface.parse::<IpAddr>() {
-        if !ip.is_unspecified() {
-            params.subject_alt_names.push(SanType::IpAddress(ip));
-        }
+fn unauthorized(challenge: bool) -> Response {
+    let mut resp = Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(Body::from("Unauthorized"))
+        .unwrap();
+    if challenge {
+        resp.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static(r#"Basic realm="lantrix""#),
+        );
     }
+    resp.extensions_mut()
+        .insert(ErrorMessage("Unauthorized".into()));
+    resp
+}
 
-    let key_pair = rcgen::KeyPair::generate()?;
-    let cert = params.self_signed(&key_pair)?;
-
-    let cert_pem = cert.pem();
-    let key_pem = key_pair.serialize_pem();
+fn too_many_attempts(wait: std::time::Duration) -> Response {
+    let mut resp = error(
+        StatusCode::TOO_MANY_REQUESTS,
+        "Too many failed login attempts",
+    );
+    // Round up so clients never retry a moment too early.
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    resp.headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    resp
+}
 
-    let tls = RustlsConfig::from_pem(
-        cert_pem.clone().into_bytes(),
-        key_pem.clone().into_bytes(),
-    )
-    .await?;
+// True when the client ranks application/json above text/html in Accept.
+// Wildcards don't count for either side, so browsers and curl get HTML.
+fn prefers_json(headers: &HeaderMap) -> bool {
+    let json_q = accept_q(headers, "application/json");
+    json_q > 0.0 && json_q > accept_q(headers, "text/html")
+}
 
-    Ok((tls, cert_pem, key_pem))
+// The q-value the Accept header gives an exact media type (0 when absent;
+// wildcards are ignored so that */* doesn't count as a preference).
+fn accept_q(headers: &HeaderMap, media: &str) -> f32 {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return 0.0;
+    };
+    let mut best: f32 = 0.0;
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        if !name.eq_ignore_ascii_case(media) {
+            continue;
+        }
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        best = best.max(q);
+    }
+    best
 }
 
-async fn serve_root(Ext(state): Ext<Arc<AppState>>, headers: HeaderMap) -> Response {
-    serve_rel_path(state, headers, "").await
+async fn find_index_file(dir: &Path, names: &[String]) -> Option<PathBuf> {
+    for name in names {
+        let p = dir.join(name);
+        if tokio::fs::metadata(&p).await.is_ok_and(|m| m.is_file()) {
+            return Some(p);
+        }
+    }
+    None
 }
 
-async fn serve_path(
-    Ext(state): Ext<Arc<AppState>>,
-    headers: HeaderMap,
-    AxumPath(path): AxumPath<String>,
+async fn serve_file(
+    state: &AppState,
+    path: &Path,
+    headers: &HeaderMap,
+    access: &Access,
 ) -> Response {
-    serve_rel_path(state, headers, &path).await
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let mime = mime.as_ref();
+    let mut vary = state.compress && compress::is_compressible(mime);
+
+    if state.precompressed {
+        let sidecars = find_precompressed(&state.root, path, access).await;
+        if !sidecars.is_empty() {
+            vary = true;
+            let available: Vec<Encoding> = sidecars.iter().map(|(e, _)| *e).collect();
+            if let Some(enc) = compress::negotiate(headers, &available) {
+                if let Some((_, sidecar)) = sidecars.iter().find(|(e, _)| *e == enc) {
+                    return serve_file_as(state, sidecar, mime, Some(enc), vary, headers).await;
+                }
+            }
+        }
+    }
+
+    serve_file_as(state, path, mime, None, vary, headers).await
 }
 
-async fn serve_rel_path(state: Arc<AppState>, headers: HeaderMap, rel: &str) -> Response {
-    if let Some(cfg) = &state.auth {
-        if !is_authorized(&headers, cfg) {
-            return unauthorized();
+// Serve `path` with the given Content-Type. `precoded` is set when the file is
+// a precompressed sidecar, in which case ranges and validators apply to the
+// encoded bytes.
+async fn serve_file_as(
+    state: &AppState,
+    path: &Path,
+    mime: &str,
+    precoded: Option<Encoding>,
+    vary: bool,
+    headers: &HeaderMap,
+) -> Response {
+    let file = match tokio::fs::File::open(path).await {
+        Ok(f) => f,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read file"),
+    };
+    let meta = match file.metadata().await {
+        Ok(m) => m,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read file"),
+    };
+    let len = meta.len();
+
+    let encoding = if precoded.is_none()
+        && state.compress
+        && compress::is_compressible(mime)
+        && len >= compress::MIN_COMPRESS_BYTES
+        && !headers.contains_key(header::RANGE)
+    {
+        compress::negotiate(headers, &Encoding::ALL)
+    } else {
+        None
+    };
+
+    let mut validators = file_validators(state, path, &meta).await;
+    if let Some(enc) = precoded.or(encoding) {
+        validators = validators.for_encoding(enc.as_str());
+    }
+    if let Some(status) = conditional::evaluate(headers, &validators) {
+        let mut resp = precondition_response(status, &validators);
+        if vary {
+            resp.headers_mut()
+                .insert(header::VARY, HeaderValue::from_static("accept-encoding"));
         }
+        return resp;
     }
 
-    if !state.console && (rel.starts_with("__console") || rel.starts_with("/__console")) {
-        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    // Compressed output has no known length, so it is always a full 200 without
+    // range support; Range requests are answered from the identity encoding.
+    if let Some(enc) = encoding {
+        let mut resp = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, mime)
+            .header(header::CONTENT_ENCODING, enc.as_str())
+            .header(header::VARY, "accept-encoding")
+            .body(compress::encode_body(enc, BufReader::new(file)))
+            .unwrap();
+        validators.apply(resp.headers_mut());
+        return resp;
     }
 
-    let decoded = match urlencoding::decode(rel) {
-        Ok(s) => s.into_owned(),
-        Err(_) => return (StatusCode::BAD_REQUEST, "Bad URL encoding").into_response(),
+    let digest = repr_digest(state, path, &meta, headers).await;
+
+    let outcome = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(r) if conditional::if_range_matches(headers, &validators) => {
+            range::parse_range(r, len)
+        }
+        _ => RangeOutcome::Full,
     };
 
-    let candidate = state.root.join(&decoded);
+    // Bodies are streamed in bounded chunks so memory stays flat regardless of
+    // file size. hyper owns the socket, so there is no sendfile/splice path here.
+    let mut resp = match outcome {
+        RangeOutcome::Full => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, mime)
+            .header(header::CONTENT_LENGTH, len)
+            .header(header::ACCEPT_RANGES, "bytes")
+            .body(Body::from_stream(ReaderStream::with_capacity(
+                file,
+                STREAM_CHUNK_BYTES,
+            )))
+            .unwrap(),
+        RangeOutcome::Unsatisfiable => Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+            .header(header::ACCEPT_RANGES, "bytes")
+            .body(Body::empty())
+            .unwrap(),
+        RangeOutcome::Partial(ranges) if ranges.len() == 1 => {
+            let r = ranges[0];
+            let stream = match range_stream(file, r).await {
+                Ok(s) => s,
+                Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read file"),
+            };
+            Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, mime)
+                .header(header::CONTENT_LENGTH, r.len())
+                .header(header::CONTENT_RANGE, r.content_range(len))
+                .header(header::ACCEPT_RANGES, "bytes")
+                .body(Body::from_stream(stream))
+                .unwrap()
+        }
+        RangeOutcome::Partial(ranges) => {
+            let boundary = multipart_boundary();
+            let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+            let mut total: u64 = 0;
+
+            for r in ranges {
+                // Each part gets its own handle; duplicated fds would share the cursor.
+                let part_file = match tokio::fs::File::open(path).await {
+                    Ok(f) => f,
+                    Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read file"),
+                };
+                let stream = match range_stream(part_file, r).await {
+                    Ok(s) => s,
+                    Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read file"),
+                };
+                let head = format!(
+                    "\r\n--{boundary}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    mime,
+                    r.content_range(len)
+                );
+                total += head.len() as u64 + r.len();
+                parts.push(stream::once(async move { Ok(Bytes::from(head)) }).boxed());
+                parts.push(stream.boxed());
+            }
 
-    let meta = match tokio::fs::metadata(&candidate).await {
-        Ok(m) => m,
-        Err(_) => return (StatusCode::NOT_FOUND, "Not found").into_response(),
-    };
+            let tail = format!("\r\n--{boundary}--\r\n");
+            total += tail.len() as u64;
+            parts.push(stream::once(async move { Ok(Bytes::from(tail)) }).boxed());
 
-    let canon = match tokio::fs::canonicalize(&candidate).await {
-        Ok(p) => p,
-        Err(_) => return (StatusCode::FORBIDDEN, "Forbidden").into_response(),
+            Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/byteranges; boundary={boundary}"),
+                )
+                .header(header::CONTENT_LENGTH, total)
+                .header(header::ACCEPT_RANGES, "bytes")
+                .body(Body::from_stream(stream::iter(parts).flatten()))
+                .unwrap()
+        }
     };
 
-    if !canon.starts_with(&state.root) {
-        return (StatusCode::FORBIDDEN, "Forbidden").into_response();
+    if resp.status().is_success() {
+        validators.apply(resp.headers_mut());
+        if let Some(enc) = precoded {
+            resp.headers_mut().insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(enc.as_str()),
+            );
+        }
+        if let Some((repr, legacy)) = digest
+            .as_deref()
+            .and_then(|d| checksum::digest_headers(checksum::Algorithm::Sha256, d))
+        {
+            let h = resp.headers_mut();
+            if let (Ok(repr), Ok(legacy)) = (repr.parse(), legacy.parse()) {
+                h.insert(HeaderName::from_static("repr-digest"), repr);
+                h.insert(HeaderName::from_static("digest"), legacy);
+            }
+        }
     }
+    if vary {
+        resp.headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    resp
+}
 
-    if meta.is_dir() {
-        if let Some(index) = find_index_file(&canon).await {
-            return serve_file(&index).await;
+// Existing precompressed siblings of `path` (e.g. app.js.br), in server
+// preference order. Sidecars must resolve inside the served root, to
+// somewhere the client may read.
+async fn find_precompressed(root: &Path, path: &Path, access: &Access) -> Vec<(Encoding, PathBuf)> {
+    let mut found = Vec::new();
+    for enc in Encoding::ALL {
+        let mut name = path.as_os_str().to_owned();
+        name.push(enc.extension());
+        let Ok(canon) = tokio::fs::canonicalize(PathBuf::from(name)).await else {
+            continue;
+        };
+        if !canon.starts_with(root) || !access.allows(&canon, Perms::READ) {
+            continue;
+        }
+        if tokio::fs::metadata(&canon).await.is_ok_and(|m| m.is_file()) {
+            found.push((enc, canon));
         }
-        return list_dir(&state.root, &canon, state.console).await;
     }
+    found
+}
 
-    serve_file(&canon).await
+// SHA-256 of the bytes being served (the sidecar for precompressed files),
+// for Repr-Digest. Needs --checksums with sha256; then a cached digest is
+// always used, and one is computed when the client sends Want-Repr-Digest or
+// Want-Digest. Ranges still carry the digest of the whole representation.
+async fn repr_digest(
+    state: &AppState,
+    path: &Path,
+    meta: &std::fs::Metadata,
+    headers: &HeaderMap,
+) -> Option<Vec<u8>> {
+    let alg = checksum::Algorithm::Sha256;
+    if !state.checksum_algs.contains(&alg) {
+        return None;
+    }
+    if let Some(d) = state
+        .checksums
+        .cached(path, meta.len(), meta.modified().ok(), alg)
+    {
+        return Some(d);
+    }
+    if !checksum::wants_sha256(headers) {
+        return None;
+    }
+    state.checksums.digest(path, meta, alg).await.ok()
 }
 
-fn is_authorized(headers: &HeaderMap, cfg: &AuthConfig) -> bool {
-    let Some(value) = headers.get(header::AUTHORIZATION) else {
-        return false;
-    };
-    let Ok(s) = value.to_str() else {
-        return false;
-    };
-    let Some(b64) = s.strip_prefix("Basic ") else {
-        return false;
-    };
+async fn range_stream(
+    mut file: tokio::fs::File,
+    r: ByteRange,
+) -> std::io::Result<ReaderStream<tokio::io::Take<tokio::fs::File>>> {
+    file.seek(std::io::SeekFrom::Start(r.start)).await?;
+    Ok(ReaderStream::with_capacity(
+        file.take(r.len()),
+        STREAM_CHUNK_BYTES,
+    ))
+}
 
-    let Ok(decoded) = B64.decode(b64) else {
-        return false;
-    };
-    let Ok(decoded) = String::from_utf8(decoded) else {
-        return false;
+async fn file_validators(state: &AppState, path: &Path, meta: &std::fs::Metadata) -> Validators {
+    let etag = if state.etag_hash {
+        match state
+            .checksums
+            .digest(path, meta, checksum::Algorithm::Sha256)
+            .await
+        {
+            Ok(d) => conditional::digest_etag(&d),
+            Err(_) => conditional::metadata_etag(meta),
+        }
+    } else {
+        conditional::metadata_etag(meta)
     };
+    Validators::new(etag, meta.modified().ok())
+}
 
-    decoded == format!("{}:{}", cfg.user, cfg.pass)
+fn precondition_response(status: StatusCode, validators: &Validators) -> Response {
+    let mut resp = Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap();
+    validators.apply(resp.headers_mut());
+    resp
 }
 
-fn unauthorized() -> Response {
-    Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
-        .header(header::WWW_AUTHENTICATE, r#"Basic realm="lantrix""#)
-        .body(Body::from("Unauthorized"))
-        .unwrap()
+fn multipart_boundary() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("lantrix-{nanos:x}")
 }
 
-async fn find_index_file(dir: &Path) -> Option<PathBuf> {
-    for name in ["index.html", "index.htm"] {
-        let p = dir.join(name);
-        if tokio::fs::metadata(&p).await.ok()?.is_file() {
-            return Some(p);
-        }
+async fn list_dir(
+    state: &AppState,
+    dir: &Path,
+    query: &HashMap<String, String>,
+    headers: &HeaderMap,
+    access: &Access,
+) -> Response {
+    let root = state.root.as_path();
+
+    let sort = Sort::from_query(query);
+
+    if query.get("format").map(String::as_str) == Some("json") || prefers_json(headers) {
+        return list_dir_json(state, dir, query, sort, headers, access).await;
     }
-    None
-}
 
-async fn serve_file(path: &Path) -> Response {
-    match tokio::fs::read(path).await {
-        Ok(bytes) => {
-            let mime = mime_guess::from_path(path).first_or_octet_stream();
-            Response::builder()
-                .status(StatusCode::OK)
-                .header(header::CONTENT_TYPE, mime.as_ref())
-                .body(Body::from(bytes))
-                .unwrap()
+    let entries = match listing::read_sorted(dir, sort, access).await {
+        Ok(r) => r,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read directory"),
+    };
+    let (page, limit) = listing::page_from_query(query);
+    let total = entries.len();
+    let page = page.min(total.div_ceil(limit).max(1));
+    let start = (page - 1) * limit;
+    let mut shown = entries[start..(start + limit).min(total)].to_vec();
+
+    let mut latest = tokio::fs::metadata(dir)
+        .await
+        .ok()
+        .and_then(|m| m.modified().ok());
+    if sort.needs_metadata() {
+        latest = latest.max(shown.iter().filter_map(|e| e.mtime).max());
+    } else {
+        latest = latest.max(listing::stat_entries(dir, &mut shown).await);
+    }
+
+    let mut ctx = ListingContext::new(&state.prefix, root, dir, &shown, sort, state.console);
+    ctx.readme = load_readme(root, dir, &entries, access).await;
+    ctx.paginate(sort, page, limit, total);
+    ctx.account = account_link(state, access);
+    if let Some(&alg) = state.checksum_algs.first() {
+        for (item, e) in ctx.entries.iter_mut().zip(&shown) {
+            if !e.is_dir {
+                item.checksum = state
+                    .checksums
+                    .cached(&dir.join(&e.raw_name), e.size, e.mtime, alg)
+                    .map(hex::encode);
+            }
         }
-        Err(_) => (StatusCode::FORBIDDEN, "Cannot read file").into_response(),
+        ctx.checksum = Some(theme::ChecksumColumn {
+            alg: alg.as_str(),
+            label: alg.label(),
+        });
     }
+    let html = match state.theme.render_listing(&ctx) {
+        Ok(html) => html,
+        Err(e) => {
+            eprintln!("listing template error: {e:#}");
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "Template error");
+        }
+    };
+
+    generated_response(
+        state,
+        headers,
+        "text/html; charset=utf-8",
+        html.into_bytes(),
+        latest,
+    )
 }
 
-async fn list_dir(root: &Path, dir: &Path, console_enabled: bool) -> Response {
-    let mut rd = match tokio::fs::read_dir(dir).await {
-        Ok(r) => r,
-        Err(_) => return (StatusCode::FORBIDDEN, "Cannot read directory").into_response(),
+// README.md / README.txt shown below the listing, if present, small enough
+// and readable where it resolves to (it may be a symlink elsewhere).
+async fn load_readme(
+    root: &Path,
+    dir: &Path,
+    entries: &[listing::Entry],
+    access: &Access,
+) -> Option<Readme> {
+    let files = entries
+        .iter()
+        .filter(|e| !e.is_dir)
+        .map(|e| e.name.as_str());
+    let name = markdown::find_readme(files)?;
+    let canon = tokio::fs::canonicalize(dir.join(name)).await.ok()?;
+    if !canon.starts_with(root) || !access.allows(&canon, Perms::READ) {
+        return None;
+    }
+    let meta = tokio::fs::metadata(&canon).await.ok()?;
+    if !meta.is_file() || meta.len() > markdown::MAX_RENDER_BYTES {
+        return None;
+    }
+    let bytes = tokio::fs::read(&canon).await.ok()?;
+    let source = String::from_utf8_lossy(&bytes);
+    let html = if markdown::is_markdown(Path::new(name)) {
+        markdown::to_html(&source)
+    } else {
+        markdown::text_to_html(&source)
     };
+    Some(Readme {
+        name: name.to_string(),
+        html,
+    })
+}
 
-    let mut items = Vec::new();
-    while let Ok(Some(e)) = rd.next_entry().await {
-        let name = e.file_name().to_string_lossy().to_string();
-        let is_dir = e.file_type().await.map(|t| t.is_dir()).unwrap_or(false);
-        items.push((name, is_dir));
+// ?format=json (or Accept: application/json). Add recursive=1 to walk
+// subdirectories, optionally limited with depth=N; otherwise the listing is
+// paged (see list_dir_page_json).
+async fn list_dir_json(
+    state: &AppState,
+    dir: &Path,
+    query: &HashMap<String, String>,
+    sort: Sort,
+    headers: &HeaderMap,
+    access: &Access,
+) -> Response {
+    let recursive = matches!(
+        query.get("recursive").map(String::as_str),
+        Some("1" | "true" | "yes")
+    );
+    if !recursive {
+        return list_dir_page_json(state, dir, query, sort, headers, access).await;
     }
-    items.sort_by(|a, b| a.0.cmp(&b.0));
+    let depth = query
+        .get("depth")
+        .and_then(|d| d.parse::<usize>().ok())
+        .unwrap_or(listing::MAX_RECURSIVE_DEPTH)
+        .clamp(1, listing::MAX_RECURSIVE_DEPTH);
+
+    let (entries, truncated, latest) =
+        match listing::walk(&state.root, dir, depth, sort, access).await {
+            Ok(r) => r,
+            Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read directory"),
+        };
 
-    let mut html = String::new();
-    html.push_str("<!doctype html><html><head><meta charset='utf-8'>");
-    html.push_str("<title>Lantrix</title></head><body>");
-    html.push_str(&format!(
-        "<h1>Index of {}</h1><ul>",
-        display_rel(root, dir)
-    ));
+    let dir_url = listing::url_for(&state.prefix, &state.root, dir, true);
+    let body = listing::JsonListing {
+        path: display_rel(&state.root, dir),
+        entries: entries
+            .iter()
+            .map(|w| listing::JsonEntry::new(&dir_url, w))
+            .collect(),
+        truncated,
+    };
+    let json = serde_json::to_vec(&body).unwrap_or_default();
 
-    if dir != root {
-        html.push_str("<li><a href=\"../\">../</a></li>");
-    }
+    generated_response(state, headers, "application/json", json, latest)
+}
 
-    if console_enabled {
-        html.push_str("<li><a href=\"/__console\">__console</a></li>");
+// One page of a directory as JSON: ?limit=N with either ?cursor= (the
+// next_cursor of the previous page) or ?page=N. The body is streamed, each
+// entry stat()ed as it is written, so a page of a huge directory starts
+// going out as soon as the names are sorted. Streamed bodies have no ETag.
+async fn list_dir_page_json(
+    state: &AppState,
+    dir: &Path,
+    query: &HashMap<String, String>,
+    sort: Sort,
+    headers: &HeaderMap,
+    access: &Access,
+) -> Response {
+    if query.get("sort").map(String::as_str) == Some("none") {
+        return list_dir_unsorted_json(state, dir, query, headers, access).await;
     }
+    let entries = match listing::read_sorted(dir, sort, access).await {
+        Ok(r) => r,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read directory"),
+    };
+    let (page, limit) = listing::page_from_query(query);
+    let start = match query.get("cursor") {
+        Some(token) => match listing::Cursor::decode(token) {
+            Some(cursor) => cursor.position(&entries, sort),
+            None => return error(StatusCode::BAD_REQUEST, "Invalid cursor"),
+        },
+        None => ((page - 1).saturating_mul(limit)).min(entries.len()),
+    };
+    let end = (start + limit).min(entries.len());
+    let shown = entries[start..end].to_vec();
+    // Name and type orderings don't look at size/mtime, so the cursor can be
+    // taken before the entry is stat()ed.
+    let next_cursor = shown
+        .last()
+        .filter(|_| end < entries.len())
+        .map(|e| listing::Cursor::after(e).encode());
+
+    let head = format!(
+        "{{\"path\":{},\"total\":{},\"next_cursor\":{},\"truncated\":false,\"entries\":[",
+        serde_json::Value::from(display_rel(&state.root, dir)),
+        entries.len(),
+        serde_json::Value::from(next_cursor),
+    );
 
-    for (name, is_dir) in items {
-        let display = if is_dir { format!("{}/", name) } else { name.clone() };
-        let href = if is_dir {
-            format!("{}{}", urlencoding::encode(&name), "/")
-        } else {
-            urlencoding::encode(&name).to_string()
-        };
-        html.push_str(&format!(
-            "<li><a href=\"{href}\">{}</a></li>",
-            html_escape(&display)
-        ));
+    let dir = dir.to_path_buf();
+    let dir_url = listing::url_for(&state.prefix, &state.root, &dir, true);
+    let stat = !sort.needs_metadata();
+    let rows = stream::iter(shown.into_iter().enumerate()).then(move |(i, mut e)| {
+        let dir = dir.clone();
+        let dir_url = dir_url.clone();
+        async move {
+            if stat {
+                listing::stat_entry(&dir, &mut e).await;
+            }
+            let walked = listing::Walked {
+                rel: e.name.clone(),
+                href: e.href(),
+                path: dir.join(&e.raw_name),
+                entry: e,
+            };
+            let mut row = if i == 0 { Vec::new() } else { b",".to_vec() };
+            serde_json::to_writer(&mut row, &listing::JsonEntry::new(&dir_url, &walked))
+                .unwrap_or_default();
+            Ok::<_, std::io::Error>(Bytes::from(row))
+        }
+    });
+    let body = stream::once(async move { Ok(Bytes::from(head)) })
+        .chain(rows)
+        .chain(stream::once(async { Ok(Bytes::from_static(b"]}")) }))
+        .boxed();
+    streamed_json(state, headers, body)
+}
+
+// ?sort=none: entries in directory order, written as `read_dir` yields them,
+// so the first ones go out before a huge directory has been enumerated.
+// `total` is unknown (null) and `next_cursor` comes last, once it is known
+// whether more entries follow.
+async fn list_dir_unsorted_json(
+    state: &AppState,
+    dir: &Path,
+    query: &HashMap<String, String>,
+    headers: &HeaderMap,
+    access: &Access,
+) -> Response {
+    let rd = match tokio::fs::read_dir(dir).await {
+        Ok(rd) => rd,
+        Err(_) => return error(StatusCode::FORBIDDEN, "Cannot read directory"),
+    };
+    let (page, limit) = listing::page_from_query(query);
+    let skip = match query.get("cursor") {
+        Some(token) => match listing::decode_offset(token) {
+            Some(n) => n,
+            None => return error(StatusCode::BAD_REQUEST, "Invalid cursor"),
+        },
+        None => (page - 1).saturating_mul(limit),
+    };
+
+    let head = format!(
+        "{{\"path\":{},\"total\":null,\"truncated\":false,\"entries\":[",
+        serde_json::Value::from(display_rel(&state.root, dir)),
+    );
+    let dir = dir.to_path_buf();
+    let dir_url = listing::url_for(&state.prefix, &state.root, &dir, true);
+    let access = access.clone();
+
+    // (read_dir, visible entries seen, entries written, finished)
+    let rows = stream::unfold(
+        (rd, 0usize, 0usize, false),
+        move |(mut rd, mut seen, written, done)| {
+            let (dir, dir_url, access) = (dir.clone(), dir_url.clone(), access.clone());
+            async move {
+                if done {
+                    return None;
+                }
+                loop {
+                    let Ok(Some(de)) = rd.next_entry().await else {
+                        let tail = Bytes::from_static(b"],\"next_cursor\":null}");
+                        return Some((Ok::<_, std::io::Error>(tail), (rd, seen, written, true)));
+                    };
+                    let Some(mut e) = listing::visible_entry(&dir, &de, &access).await else {
+                        continue;
+                    };
+                    if seen < skip {
+                        seen += 1;
+                        continue;
+                    }
+                    if written == limit {
+                        let tail = format!(
+                            "],\"next_cursor\":{}}}",
+                            serde_json::Value::from(listing::encode_offset(seen))
+                        );
+                        return Some((Ok(Bytes::from(tail)), (rd, seen, written, true)));
+                    }
+                    listing::stat_entry(&dir, &mut e).await;
+                    let walked = listing::Walked {
+                        rel: e.name.clone(),
+                        href: e.href(),
+                        path: dir.join(&e.raw_name),
+                        entry: e,
+                    };
+                    let mut row = if written == 0 {
+                        Vec::new()
+                    } else {
+                        b",".to_vec()
+                    };
+                    serde_json::to_writer(&mut row, &listing::JsonEntry::new(&dir_url, &walked))
+                        .unwrap_or_default();
+                    return Some((Ok(Bytes::from(row)), (rd, seen + 1, written + 1, false)));
+                }
+            }
+        },
+    );
+    let body = stream::once(async move { Ok(Bytes::from(head)) })
+        .chain(rows)
+        .boxed();
+    streamed_json(state, headers, body)
+}
+
+// Streamed JSON listings, compressed on the fly when enabled. No ETag, since
+// the body isn't known up front.
+fn streamed_json(
+    state: &AppState,
+    headers: &HeaderMap,
+    body: stream::BoxStream<'static, std::io::Result<Bytes>>,
+) -> Response {
+    let encoding = if state.compress {
+        compress::negotiate(headers, &Encoding::ALL)
+    } else {
+        None
+    };
+    let mut resp = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json");
+    let body = match encoding {
+        Some(enc) => {
+            resp = resp.header(header::CONTENT_ENCODING, enc.as_str());
+            compress::encode_body(enc, StreamReader::new(body))
+        }
+        None => Body::from_stream(body),
+    };
+    let vary = if state.compress {
+        "accept, accept-encoding"
+    } else {
+        "accept"
+    };
+    resp.header(header::VARY, vary).body(body).unwrap()
+}
+
+// Response for an in-memory document (listings): weak ETag over the bytes,
+// conditional handling and optional compression.
+fn generated_response(
+    state: &AppState,
+    headers: &HeaderMap,
+    content_type: &'static str,
+    body: Vec<u8>,
+    latest: Option<std::time::SystemTime>,
+) -> Response {
+    let mut validators = Validators::new(conditional::weak_etag(&body), latest);
+    let encoding = if state.compress && body.len() as u64 >= compress::MIN_COMPRESS_BYTES {
+        compress::negotiate(headers, &Encoding::ALL)
+    } else {
+        None
+    };
+    if let Some(enc) = encoding {
+        validators = validators.for_encoding(enc.as_str());
     }
 
-    html.push_str("</ul></body></html>");
-    (StatusCode::OK, Html(html)).into_response()
+    let mut resp = if let Some(status) = conditional::evaluate(headers, &validators) {
+        precondition_response(status, &validators)
+    } else if let Some(enc) = encoding {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_ENCODING, enc.as_str())
+            .body(compress::encode_body(enc, std::io::Cursor::new(body)))
+            .unwrap()
+    } else {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    validators.apply(resp.headers_mut());
+    // Listings are negotiated on Accept (HTML vs JSON) as well.
+    let vary = if state.compress {
+        "accept, accept-encoding"
+    } else {
+        "accept"
+    };
+    resp.headers_mut()
+        .insert(header::VARY, HeaderValue::from_static(vary));
+    resp
 }
 
 fn display_rel(root: &Path, dir: &Path) -> String {
@@ -391,21 +2595,30 @@ fn html_escape(s: &str) -> String {
    Restricted Web Console
    ------------------------- */
 
-async fn console_page(Ext(state): Ext<Arc<AppState>>, headers: HeaderMap) -> Response {
-    if let Some(cfg) = &state.auth {
-        if !is_authorized(&headers, cfg) {
-            return unauthorized();
-        }
-    }
+async fn console_page(
+    Ext(state): Ext<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+) -> Response {
+    let access = match identify(&state, &headers, client.ip()).await {
+        Ok(a) => a,
+        Err(resp) => return login_redirect(&state, &headers, &uri, resp),
+    };
     if !state.console {
         return (StatusCode::NOT_FOUND, "Not found").into_response();
     }
+    // The page is for those who may run commands or upload somewhere.
+    if !access.anywhere(Perms::ADMIN | Perms::UPLOAD) {
+        return login_redirect(&state, &headers, &uri, denied(&state, &access));
+    }
 
     let html = r#"<!doctype html>
 <html>
 <head>
   <meta charset="utf-8"/>
   <meta name="viewport" content="width=device-width, initial-scale=1"/>
+  <meta name="csrf-token" content="{{csrf}}"/>
   <title>Lantrix Console</title>
   <style>
     body { margin: 0; font-family: ui-monospace, SFMono-Regular, Menlo, Monaco, Consolas, "Liberation Mono", "Courier New", monospace; }
@@ -439,7 +2652,7 @@ async fn console_page(Ext(state): Ext<Arc<AppState>>, headers: HeaderMap) -> Res
       <span class="small" id="upmsg"></span>
     </div>
 
-    <div class="hint">Restricted console (read-only + upload). Back to <a href="/">/</a></div>
+    <div class="hint">Restricted console (read-only + upload). Back to <a href="{{prefix}}/">/</a></div>
   </div>
 </div>
 
@@ -454,6 +2667,8 @@ const upmsg = document.getElementById('upmsg');
 
 function printLine(s){ out.textContent += s + "\n"; out.scrollTop = out.scrollHeight; }
 
+const csrf = document.querySelector('meta[name="csrf-token"]').content;
+
 async function execCmd(line){
   const trimmed = line.trim();
   if(!trimmed) return;
@@ -464,9 +2679,9 @@ async function execCmd(line){
   const cmd = parts[0];
   const arg = parts.slice(1).join(" ");
 
-  const res = await fetch("/__console/api", {
+  const res = await fetch("{{prefix}}/__console/api", {
     method: "POST",
-    headers: { "Content-Type": "application/json" },
+    headers: { "Content-Type": "application/json", "X-CSRF-Token": csrf },
     body: JSON.stringify({ cmd, arg })
   });
 
@@ -484,7 +2699,7 @@ async function uploadFile(){
   fd.append("dir", dest.value || ".");
   fd.append("file", file.files[0]);
 
-  const res = await fetch("/__console/upload", { method:"POST", body: fd });
+  const res = await fetch("{{prefix}}/__console/upload", { method:"POST", headers: { "X-CSRF-Token": csrf }, body: fd });
   const data = await res.json().catch(() => ({ ok:false, out:"Bad response" }));
   upmsg.textContent = data.ok ? "Uploaded." : ("Upload failed: " + (data.out || ""));
   if(data.out) printLine(data.out);
@@ -501,6 +2716,15 @@ printLine("Lantrix console. Type 'help'. Upload below.");
 </html>
 "#;
 
+    // The prefix is percent-encoded, so it is safe in both the HTML and JS.
+    // The CSRF token is base64url, likewise safe.
+    let csrf = session(&state, &headers)
+        .await
+        .map(|(_, s)| s.csrf)
+        .unwrap_or_default();
+    let html = html
+        .replace("{{prefix}}", &state.prefix)
+        .replace("{{csrf}}", &csrf);
     (StatusCode::OK, Html(html)).into_response()
 }
 
@@ -519,28 +2743,49 @@ struct ConsoleResp {
 
 async fn console_api(
     Ext(state): Ext<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
     Json(req): Json<ConsoleReq>,
 ) -> Response {
-    if let Some(cfg) = &state.auth {
-        if !is_authorized(&headers, cfg) {
-            return unauthorized();
-        }
-    }
+    let access = match identify(&state, &headers, client.ip()).await {
+        Ok(a) => a,
+        Err(resp) => return resp,
+    };
     if !state.console {
         return (StatusCode::NOT_FOUND, "Not found").into_response();
     }
+    if !csrf_ok(&state, &headers, None).await {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid CSRF token");
+    }
 
     let cmd = req.cmd.trim().to_lowercase();
     let arg = req.arg.trim().to_string();
+    // Commands need admin on the path they look at: checked on the requested
+    // path before touching the disk, so existence isn't revealed, and again
+    // on where it resolves to.
+    let forbidden = || {
+        Json(ConsoleResp {
+            ok: false,
+            out: "Forbidden (admin permission required)".to_string(),
+        })
+        .into_response()
+    };
 
     let out = match cmd.as_str() {
         "help" => {
-            "Commands:\n  help\n  pwd\n  ls [path]\n  cat <file>\n\nUploads:\n  Use the upload UI below (POST /__console/upload).\n\nAll paths are restricted to the served root.\nNo shell execution."
+            "Commands:\n  help\n  pwd\n  ls [path]\n  cat <file>\n  share [30m|12h|7d] [max=N] [ip=ADDR] <path>\n  revoke <id>\n\nUploads:\n  Use the upload UI below (POST /__console/upload).\n\nAll paths are restricted to the served root.\nNo shell execution."
                 .to_string()
         }
-        "pwd" => format!("{}", state.root.display()),
+        "pwd" => {
+            if !access.allows(&state.root, Perms::ADMIN) {
+                return forbidden();
+            }
+            format!("{}", state.root.display())
+        }
         "ls" => {
+            if !access.allows(&state.root.join(&arg), Perms::ADMIN) {
+                return forbidden();
+            }
             let p = if arg.is_empty() {
                 state.root.clone()
             } else {
@@ -549,8 +2794,11 @@ async fn console_api(
                     Err(e) => return Json(ConsoleResp { ok: false, out: e }).into_response(),
                 }
             };
+            if !access.allows(&p, Perms::ADMIN) {
+                return forbidden();
+            }
 
-            match list_dir_plain(&state.root, &p).await {
+            match list_dir_plain(&state.root, &p, &access).await {
                 Ok(s) => s,
                 Err(e) => e,
             }
@@ -558,8 +2806,11 @@ async fn console_api(
         "cat" => {
             if arg.is_empty() {
                 "Usage: cat <file>".to_string()
+            } else if !access.allows(&state.root.join(&arg), Perms::ADMIN) {
+                return forbidden();
             } else {
                 match safe_join(&state.root, &arg).await {
+                    Ok(p) if !access.allows(&p, Perms::ADMIN) => return forbidden(),
                     Ok(p) => match cat_file_limited(&p, 256 * 1024).await {
                         Ok(s) => s,
                         Err(e) => e,
@@ -568,12 +2819,89 @@ async fn console_api(
                 }
             }
         }
+        "share" => match console_share(&state, &headers, &access, &arg).await {
+            Ok(out) => out,
+            Err(resp) => return resp,
+        },
+        "revoke" => match &state.shares {
+            None => "Share links are disabled (start with --share-key FILE).".to_string(),
+            Some(_) if !access.allows(&state.root, Perms::ADMIN) => return forbidden(),
+            Some(_) if arg.is_empty() => "Usage: revoke <id>".to_string(),
+            Some(shares) if shares.revoke(&arg) => format!("Revoked {arg}"),
+            Some(_) => format!("{arg} was already revoked"),
+        },
         _ => "Unknown command. Type 'help'.".to_string(),
     };
 
     Json(ConsoleResp { ok: true, out }).into_response()
 }
 
+// share [DURATION] [max=N] [ip=ADDR] <path>: options come first, the rest of
+// the line is the path (which may contain spaces).
+async fn console_share(
+    state: &AppState,
+    headers: &HeaderMap,
+    access: &Access,
+    arg: &str,
+) -> Result<String, Response> {
+    let Some(shares) = &state.shares else {
+        return Ok("Share links are disabled (start with --share-key FILE).".to_string());
+    };
+    let usage = "Usage: share [30m|12h|7d] [max=N] [ip=ADDR] <path>";
+
+    let (mut ttl, mut max, mut ip) = (std::time::Duration::from_secs(24 * 60 * 60), None, None);
+    let mut rest = arg.trim();
+    while let Some((word, tail)) = rest.split_once(char::is_whitespace) {
+        if let Some(n) = word.strip_prefix("max=") {
+            match n.parse() {
+                Ok(n) => max = Some(n),
+                Err(_) => return Ok(usage.to_string()),
+            }
+        } else if let Some(a) = word.strip_prefix("ip=") {
+            match a.parse() {
+                Ok(a) => ip = Some(a),
+                Err(_) => return Ok(usage.to_string()),
+            }
+        } else if let Ok(d) = parse_duration(word) {
+            ttl = d;
+        } else {
+            break;
+        }
+        rest = tail.trim_start();
+    }
+    if rest.is_empty() {
+        return Ok(usage.to_string());
+    }
+
+    let target = match tokio::fs::canonicalize(state.root.join(rest)).await {
+        Ok(p) if p.starts_with(&state.root) => p,
+        Ok(_) => return Ok("Forbidden (outside root)".to_string()),
+        Err(_) => return Ok("Not found / not accessible".to_string()),
+    };
+    if !access.allows(&target, Perms::ADMIN) {
+        return Err(Json(ConsoleResp {
+            ok: false,
+            out: "Forbidden (admin permission required)".to_string(),
+        })
+        .into_response());
+    }
+
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("localhost");
+    let scheme = if state.https { "https" } else { "http" };
+    let is_dir = tokio::fs::metadata(&target).await.is_ok_and(|m| m.is_dir());
+    let token = shares.mint(share_path(&state.root, &target), ttl, max, ip);
+    let base = format!("{scheme}://{host}");
+    Ok(format!(
+        "{}\nid {}, {}",
+        share_link(&base, &state.prefix, &state.root, &target, is_dir, &token),
+        token.id,
+        share_summary(&token)
+    ))
+}
+
 #[derive(Serialize)]
 struct UploadResp {
     ok: bool,
@@ -585,17 +2913,20 @@ struct UploadResp {
 // - file: file to upload (required)
 async fn console_upload(
     Ext(state): Ext<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
     mut mp: Multipart,
 ) -> Response {
-    if let Some(cfg) = &state.auth {
-        if !is_authorized(&headers, cfg) {
-            return unauthorized();
-        }
-    }
+    let access = match identify(&state, &headers, client.ip()).await {
+        Ok(a) => a,
+        Err(resp) => return resp,
+    };
     if !state.console {
         return (StatusCode::NOT_FOUND, "Not found").into_response();
     }
+    if !csrf_ok(&state, &headers, None).await {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid CSRF token");
+    }
 
     let mut dir_rel: String = ".".to_string();
     let mut saved_path: Option<PathBuf> = None;
@@ -642,6 +2973,9 @@ async fn console_upload(
                 Ok(p) => p,
                 Err(e) => return Json(UploadResp { ok: false, out: e }).into_response(),
             };
+            if !access.allows(&target_dir, Perms::UPLOAD) {
+                return denied(&state, &access);
+            }
 
             let dest_path = target_dir.join(&file_name);
 
@@ -764,11 +3098,11 @@ fn sanitize_filename(name: &str) -> Option<String> {
         return None;
     }
     // very small hardening: strip path separators if any slipped in
-    let n = n.replace('/', "_").replace('\\', "_");
+    let n = n.replace(['/', '\\'], "_");
     Some(n)
 }
 
-async fn list_dir_plain(root: &Path, dir: &Path) -> Result<String, String> {
+async fn list_dir_plain(root: &Path, dir: &Path, access: &Access) -> Result<String, String> {
     let meta = tokio::fs::metadata(dir)
         .await
         .map_err(|_| "Not found".to_string())?;
@@ -776,17 +3110,29 @@ async fn list_dir_plain(root: &Path, dir: &Path) -> Result<String, String> {
         return Err("Not a directory".to_string());
     }
 
-    let mut rd = tokio::fs::read_dir(dir)
+    // Same entries and ordering as the HTML/JSON listings.
+    let (mut entries, _) = listing::read_entries(dir, access)
         .await
         .map_err(|_| "Cannot read directory".to_string())?;
+    listing::sort_entries(&mut entries, Sort::default());
 
-    let mut items: Vec<String> = Vec::new();
-    while let Ok(Some(e)) = rd.next_entry().await {
-        let name = e.file_name().to_string_lossy().to_string();
-        let is_dir = e.file_type().await.map(|t| t.is_dir()).unwrap_or(false);
-        items.push(if is_dir { format!("{name}/") } else { name });
-    }
-    items.sort();
+    let items: Vec<String> = entries
+        .iter()
+        .map(|e| {
+            let name = if e.is_dir {
+                format!("{}/", e.name)
+            } else {
+                e.name.clone()
+            };
+            let size = if e.is_dir {
+                "-".to_string()
+            } else {
+                listing::human_size(e.size)
+            };
+            let mtime = e.mtime.map(listing::format_mtime).unwrap_or_default();
+            format!("{name:<40} {size:>10}  {mtime}")
+        })
+        .collect();
 
     let rel = display_rel(root, dir);
     Ok(format!("{rel}\n{}", items.join("\n")))
@@ -809,3 +3155,42 @@ async fn cat_file_limited(path: &Path, max_bytes: usize) -> Result<String, Strin
 
     Ok(String::from_utf8_lossy(&bytes).to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_path_collapses_leading_slashes() {
+        let path = |s: &str| local_path(&s.parse::<Uri>().unwrap());
+        assert_eq!(path("/docs"), "/docs");
+        assert_eq!(path("//docs"), "/docs");
+        assert_eq!(path("///docs//a?x=1"), "/docs//a");
+        assert_eq!(path("/"), "/");
+    }
+
+    #[test]
+    fn safe_next_stays_local() {
+        let next = |prefix, n| safe_next_path(prefix, Some(n));
+        assert_eq!(next("", "/a/b?c=d"), "/a/b?c=d");
+        assert_eq!(next("/files", "/files/a%20b"), "/files/a%20b");
+        assert_eq!(safe_next_path("/files", None), "/files/");
+
+        for bad in [
+            "//evil.example",
+            "/\\evil.example",
+            "/\t/evil.example",
+            "/\n/evil.example",
+            "/ /evil.example",
+            "/\u{7f}/evil.example",
+            "https://evil.example/",
+            "evil.example",
+            "",
+        ] {
+            assert_eq!(next("", bad), "/", "{bad:?}");
+        }
+        // outside the prefix
+        assert_eq!(next("/files", "/other"), "/files/");
+        assert_eq!(next("/files", "/filesx/a"), "/files/");
+    }
+}
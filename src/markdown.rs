@@ -0,0 +1,181 @@
+// Markdown rendering for README panels and the ?render=1 file view. Raw HTML
+// in the source is shown as text rather than passed through, and link or image
+// targets with a script-capable scheme are dropped, so a served file cannot
+// inject script into the page.
+
+use std::path::Path;
+
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+
+// Larger files are served raw; rendering is meant for documents, not dumps.
+pub const MAX_RENDER_BYTES: u64 = 2 * 1024 * 1024;
+
+// README candidates, in order of preference (matched case-insensitively).
+const README_NAMES: [&str; 4] = ["readme.md", "readme.markdown", "readme.txt", "readme"];
+
+pub fn is_markdown(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("md") || e.eq_ignore_ascii_case("markdown"))
+}
+
+// Picks the README to show below a listing from the directory's file names.
+pub fn find_readme<'a>(names: impl Iterator<Item = &'a str> + Clone) -> Option<&'a str> {
+    README_NAMES
+        .iter()
+        .find_map(|want| names.clone().find(|n| n.eq_ignore_ascii_case(want)))
+}
+
+pub fn to_html(source: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+
+    let events = Parser::new_ext(source, options).map(|event| match event {
+        Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        other => other,
+    });
+
+    let mut out = String::with_capacity(source.len() * 3 / 2);
+    html::push_html(&mut out, events);
+    out
+}
+
+// Plain-text READMEs are shown preformatted.
+pub fn text_to_html(source: &str) -> String {
+    format!("<pre>{}</pre>", crate::html_escape(source))
+}
+
+// Relative URLs and a short list of schemes pass; anything else (javascript:,
+// vbscript:, data:, ...) becomes an inert "#".
+fn safe_url(url: CowStr<'_>) -> CowStr<'_> {
+    // Browsers ignore tabs, newlines and leading spaces/control characters
+    // when parsing the scheme, so do the same before looking at it.
+    let compact: String = url
+        .chars()
+        .filter(|c| !c.is_ascii_control() && !c.is_whitespace())
+        .collect();
+    let scheme_end = compact.find([':', '/', '?', '#']);
+    let allowed = match scheme_end {
+        Some(i) if compact[i..].starts_with(':') => {
+            let scheme = compact[..i].to_ascii_lowercase();
+            matches!(scheme.as_str(), "http" | "https" | "mailto" | "ftp")
+        }
+        _ => true,
+    };
+    if allowed {
+        url
+    } else {
+        CowStr::Borrowed("#")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn safe(url: &str) -> String {
+        safe_url(CowStr::Borrowed(url)).to_string()
+    }
+
+    #[test]
+    fn unsafe_schemes_are_dropped() {
+        for url in [
+            "javascript:alert(1)",
+            "JaVaScRiPt:alert(1)",
+            " javascript:alert(1)",
+            "java\tscript:alert(1)",
+            "java\nscript:alert(1)",
+            "\u{1}javascript:alert(1)",
+            "vbscript:msgbox(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "DATA:image/svg+xml;base64,PHN2Zz4=",
+        ] {
+            assert_eq!(safe(url), "#", "{url:?}");
+        }
+        for url in [
+            "https://example.com/a",
+            "HTTP://example.com/",
+            "mailto:someone@example.com",
+            "docs/a:b.md",
+            "/abs/path?x=javascript:1",
+            "#javascript:x",
+            "?q=data:x",
+            "",
+        ] {
+            assert_eq!(safe(url), url, "{url:?}");
+        }
+    }
+
+    #[test]
+    fn links_and_images_are_sanitized() {
+        let html = to_html(
+            "[a](javascript:alert(1)) [b](<JavaScript:alert(2)>) [c](&#106;avascript:alert(3))",
+        );
+        assert!(!html.to_ascii_lowercase().contains("javascript"), "{html}");
+        assert_eq!(html.matches(r##"href="#""##).count(), 3, "{html}");
+
+        let html = to_html("![x](data:image/svg+xml,<svg/onload=alert(1)>) ![y](pics/y.png)");
+        assert!(html.contains(r##"<img src="#" alt="x""##), "{html}");
+        assert!(html.contains(r#"<img src="pics/y.png" alt="y""#), "{html}");
+
+        let html = to_html("[ok](https://example.com/?a=1&b=2)");
+        assert!(
+            html.contains(r#"href="https://example.com/?a=1&amp;b=2""#),
+            "{html}"
+        );
+    }
+
+    #[test]
+    fn raw_html_is_escaped() {
+        let html = to_html("<script>alert(1)</script>\n\ntext <img src=x onerror=alert(1)> more\n");
+        assert!(!html.contains("<script"), "{html}");
+        assert!(!html.contains("<img"), "{html}");
+        assert!(
+            html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"),
+            "{html}"
+        );
+        assert!(
+            html.contains("&lt;img src=x onerror=alert(1)&gt;"),
+            "{html}"
+        );
+    }
+
+    #[test]
+    fn plain_text_is_escaped() {
+        assert_eq!(text_to_html("a <b> & c"), "<pre>a &lt;b&gt; &amp; c</pre>");
+    }
+
+    #[test]
+    fn readme_preference() {
+        let names = ["readme", "README.txt", "Readme.MD", "notes.md"];
+        assert_eq!(find_readme(names.iter().copied()), Some("Readme.MD"));
+        assert_eq!(find_readme(["readme", "a"].iter().copied()), Some("readme"));
+        assert_eq!(find_readme(["notes.md"].iter().copied()), None);
+        assert!(is_markdown(Path::new("a/B.Markdown")));
+        assert!(!is_markdown(Path::new("a.txt")));
+    }
+}
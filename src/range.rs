@@ -0,0 +1,195 @@
+// HTTP Range request parsing (RFC 9110 section 14).
+
+// Upper bound on the number of ranges we are willing to serve in a single
+// multipart/byteranges response; larger requests fall back to a full 200.
+const MAX_RANGES: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64, // inclusive
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeOutcome {
+    // No usable Range header: serve the whole representation.
+    Full,
+    // One or more satisfiable ranges, sorted and coalesced.
+    Partial(Vec<ByteRange>),
+    // Syntactically valid, but none of the ranges overlap the file.
+    Unsatisfiable,
+}
+
+pub fn parse_range(header: &str, len: u64) -> RangeOutcome {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        // Unknown range unit: ignore the header.
+        return RangeOutcome::Full;
+    };
+
+    let mut ranges = Vec::new();
+    let mut any_valid = false;
+
+    for part in spec.split(',') {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+        let Some((first, last)) = part.split_once('-') else {
+            return RangeOutcome::Full;
+        };
+        let (first, last) = (first.trim(), last.trim());
+
+        let range = if first.is_empty() {
+            // suffix-range: "-N" means the last N bytes
+            let Ok(suffix) = last.parse::<u64>() else {
+                return RangeOutcome::Full;
+            };
+            any_valid = true;
+            if suffix == 0 || len == 0 {
+                continue;
+            }
+            ByteRange {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            }
+        } else {
+            let Ok(start) = first.parse::<u64>() else {
+                return RangeOutcome::Full;
+            };
+            let end = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(e) if e >= start => e,
+                    _ => return RangeOutcome::Full,
+                }
+            };
+            any_valid = true;
+            if start >= len {
+                continue;
+            }
+            ByteRange {
+                start,
+                end: end.min(len - 1),
+            }
+        };
+
+        ranges.push(range);
+        if ranges.len() > MAX_RANGES {
+            return RangeOutcome::Full;
+        }
+    }
+
+    if !any_valid {
+        return RangeOutcome::Full;
+    }
+    if ranges.is_empty() {
+        return RangeOutcome::Unsatisfiable;
+    }
+
+    RangeOutcome::Partial(coalesce(ranges))
+}
+
+// Sort and merge overlapping or adjacent ranges so clients can't make us send
+// the same bytes many times over.
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for r in ranges {
+        match merged.last_mut() {
+            Some(prev) if r.start <= prev.end.saturating_add(1) => {
+                prev.end = prev.end.max(r.end);
+            }
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn r(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn single_ranges() {
+        let cases = [
+            ("bytes=0-499", RangeOutcome::Partial(vec![r(0, 499)])),
+            ("bytes=500-", RangeOutcome::Partial(vec![r(500, 999)])),
+            ("bytes=900-5000", RangeOutcome::Partial(vec![r(900, 999)])),
+            (" bytes= 10 - 19 ", RangeOutcome::Partial(vec![r(10, 19)])),
+            // suffix ranges
+            ("bytes=-100", RangeOutcome::Partial(vec![r(900, 999)])),
+            ("bytes=-5000", RangeOutcome::Partial(vec![r(0, 999)])),
+            ("bytes=-0", RangeOutcome::Unsatisfiable),
+            ("bytes=1000-", RangeOutcome::Unsatisfiable),
+            ("bytes=1000-2000,-0", RangeOutcome::Unsatisfiable),
+            // malformed or unknown: the header is ignored
+            ("bytes=5-1", RangeOutcome::Full),
+            ("bytes=abc", RangeOutcome::Full),
+            ("bytes=-", RangeOutcome::Full),
+            ("bytes=", RangeOutcome::Full),
+            ("items=0-1", RangeOutcome::Full),
+        ];
+        for (header, want) in cases {
+            assert_eq!(parse_range(header, 1000), want, "{header}");
+        }
+    }
+
+    #[test]
+    fn empty_file() {
+        assert_eq!(parse_range("bytes=0-", 0), RangeOutcome::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), RangeOutcome::Unsatisfiable);
+    }
+
+    #[test]
+    fn coalesces_overlapping_and_adjacent() {
+        assert_eq!(
+            parse_range("bytes=5-6,0-1,1-3,7-8,20-", 30),
+            RangeOutcome::Partial(vec![r(0, 3), r(5, 8), r(20, 29)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-0,0-0,0-0", 10),
+            RangeOutcome::Partial(vec![r(0, 0)])
+        );
+        assert_eq!(
+            parse_range("bytes=-5,0-", 10),
+            RangeOutcome::Partial(vec![r(0, 9)])
+        );
+    }
+
+    #[test]
+    fn too_many_ranges() {
+        let spec = |n: u64| {
+            let parts: Vec<String> = (0..n).map(|i| format!("{}-{}", i * 2, i * 2)).collect();
+            format!("bytes={}", parts.join(","))
+        };
+        match parse_range(&spec(MAX_RANGES as u64), 1000) {
+            RangeOutcome::Partial(ranges) => assert_eq!(ranges.len(), MAX_RANGES),
+            other => panic!("expected {MAX_RANGES} ranges, got {other:?}"),
+        }
+        assert_eq!(
+            parse_range(&spec(MAX_RANGES as u64 + 1), 1000),
+            RangeOutcome::Full
+        );
+    }
+
+    #[test]
+    fn content_range() {
+        assert_eq!(r(0, 499).content_range(1000), "bytes 0-499/1000");
+        assert_eq!(r(0, 499).len(), 500);
+    }
+}
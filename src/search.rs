@@ -0,0 +1,221 @@
+// Filename search (?q=pattern on a directory). Walks the tree below the
+// directory like the recursive JSON listing does: symlinked directories are
+// followed only when they resolve inside the root, each directory is visited
+// once, and matches that resolve outside the root are dropped, just as
+// serving them would be refused. Walks stop at a result limit and a deadline,
+// which is checked per entry.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use globset::{GlobBuilder, GlobMatcher};
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+
+use crate::acl::Access;
+use crate::listing::{self, JsonEntry, Sort, Walked};
+
+pub const DEFAULT_RESULTS: usize = 200;
+pub const MAX_RESULTS: usize = 1_000;
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Cap on compiled regex size, so a hostile pattern can't eat memory.
+const MAX_REGEX_BYTES: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Substring,
+    Glob,
+    Regex,
+}
+
+impl Mode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::Substring => "substring",
+            Mode::Glob => "glob",
+            Mode::Regex => "regex",
+        }
+    }
+}
+
+// Case-insensitive name matcher. Patterns containing '/' are matched against
+// the path relative to the searched directory, others against the name.
+pub enum Matcher {
+    Substring(String),
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Matcher {
+    // ?match=substring|glob|regex; without it, patterns with glob
+    // metacharacters are globs and everything else is a substring.
+    pub fn from_query(pattern: &str, mode: Option<&str>) -> Result<(Self, Mode), String> {
+        let mode = match mode {
+            Some("substring") => Mode::Substring,
+            Some("glob") => Mode::Glob,
+            Some("regex") => Mode::Regex,
+            Some(other) => return Err(format!("unknown match mode {other:?}")),
+            None if pattern.contains(['*', '?', '[']) => Mode::Glob,
+            None => Mode::Substring,
+        };
+        let matcher = match mode {
+            Mode::Substring => Matcher::Substring(pattern.to_lowercase()),
+            Mode::Glob => GlobBuilder::new(pattern)
+                .case_insensitive(true)
+                .literal_separator(true)
+                .build()
+                .map(|g| Matcher::Glob(g.compile_matcher()))
+                .map_err(|e| e.kind().to_string())?,
+            Mode::Regex => RegexBuilder::new(pattern)
+                .case_insensitive(true)
+                .size_limit(MAX_REGEX_BYTES)
+                .build()
+                .map(Matcher::Regex)
+                .map_err(|e| e.to_string())?,
+        };
+        Ok((matcher, mode))
+    }
+
+    fn matches(&self, name: &str, rel: &str) -> bool {
+        match self {
+            Matcher::Substring(s) => {
+                let target = if s.contains('/') { rel } else { name };
+                target.to_lowercase().contains(s.as_str())
+            }
+            Matcher::Glob(g) => {
+                let target = if g.glob().glob().contains('/') {
+                    rel
+                } else {
+                    name
+                };
+                g.is_match(target)
+            }
+            Matcher::Regex(r) => {
+                let target = if r.as_str().contains('/') { rel } else { name };
+                r.is_match(target)
+            }
+        }
+    }
+}
+
+// ?limit=N, clamped to 1..=MAX_RESULTS.
+pub fn limit_from_query(query: &HashMap<String, String>) -> usize {
+    query
+        .get("limit")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_RESULTS)
+        .clamp(1, MAX_RESULTS)
+}
+
+pub struct Results {
+    pub hits: Vec<Walked>,
+    // Stopped at the result limit; there may be more matches.
+    pub truncated: bool,
+    // Stopped at the deadline; part of the tree was not searched.
+    pub timed_out: bool,
+}
+
+// Depth-first over `dir`, each level in `sort` order, so results come out in
+// the same order a recursive listing would show them.
+pub async fn search(
+    root: &Path,
+    dir: &Path,
+    matcher: &Matcher,
+    limit: usize,
+    sort: Sort,
+    access: &Access,
+) -> std::io::Result<Results> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut hits = Vec::new();
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    visited.insert(dir.to_path_buf());
+
+    let mut stack: Vec<(PathBuf, String, String, usize)> =
+        vec![(dir.to_path_buf(), String::new(), String::new(), 1)];
+    let mut first = true;
+    let timed_out = |hits| Results {
+        hits,
+        truncated: false,
+        timed_out: true,
+    };
+    while let Some((path, prefix, href_prefix, depth)) = stack.pop() {
+        if Instant::now() >= deadline {
+            return Ok(timed_out(hits));
+        }
+        // Only size and mtime orders stat every entry up front; otherwise
+        // just the matches are stat()ed below.
+        let entries = match listing::read_sorted(&path, sort, access).await {
+            Ok(r) => r,
+            Err(e) if first => return Err(e),
+            Err(_) => continue,
+        };
+        first = false;
+
+        let mut subdirs = Vec::new();
+        for mut e in entries {
+            // Checked per entry too: a single huge directory, or an
+            // expensive regex, can take longer than the whole budget.
+            if Instant::now() >= deadline {
+                return Ok(timed_out(hits));
+            }
+            let rel = format!("{prefix}{}", e.name);
+            let href = format!("{href_prefix}{}", e.href());
+            let is_match = matcher.matches(&e.name, &rel);
+            if !e.is_dir && !is_match {
+                continue;
+            }
+
+            // Anything that escapes the root is neither searched nor shown.
+            let full = path.join(&e.raw_name);
+            let canon = match tokio::fs::canonicalize(&full).await {
+                Ok(c) if c.starts_with(root) => c,
+                _ => continue,
+            };
+
+            if e.is_dir && depth < listing::MAX_RECURSIVE_DEPTH && visited.insert(canon.clone()) {
+                subdirs.push((canon, format!("{rel}/"), format!("{href}/"), depth + 1));
+            }
+
+            if is_match {
+                if hits.len() >= limit {
+                    return Ok(Results {
+                        hits,
+                        truncated: true,
+                        timed_out: false,
+                    });
+                }
+                if !sort.needs_metadata() {
+                    listing::stat_entry(&path, &mut e).await;
+                }
+                hits.push(Walked {
+                    rel,
+                    href,
+                    path: full,
+                    entry: e,
+                });
+            }
+        }
+        stack.extend(subdirs.into_iter().rev());
+    }
+
+    Ok(Results {
+        hits,
+        truncated: false,
+        timed_out: false,
+    })
+}
+
+#[derive(Serialize)]
+pub struct JsonResults {
+    pub path: String,
+    pub query: String,
+    #[serde(rename = "match")]
+    pub mode: &'static str,
+    pub entries: Vec<JsonEntry>,
+    pub truncated: bool,
+    pub timed_out: bool,
+}
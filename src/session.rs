@@ -0,0 +1,93 @@
+// Form-login sessions (--login-form). A successful login creates a random
+// session id, handed out as an HttpOnly cookie, and a CSRF token that
+// state-changing console requests made with that session must echo back.
+// Sessions live in memory only, so a restart logs everyone out.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use ring::rand::{SecureRandom, SystemRandom};
+
+pub const COOKIE: &str = "lantrix_session";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+// When this many sessions exist, expired ones are swept and, if still full,
+// the one closest to expiry is dropped.
+const MAX_SESSIONS: usize = 10_000;
+
+#[derive(Clone)]
+pub struct Session {
+    pub user: String,
+    pub csrf: String,
+    expires: Instant,
+}
+
+pub struct Sessions {
+    ttl: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    // Returns the new session's id.
+    pub fn create(&self, user: &str) -> String {
+        let id = random_token();
+        let session = Session {
+            user: user.to_string(),
+            csrf: random_token(),
+            expires: Instant::now() + self.ttl,
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= MAX_SESSIONS {
+            let now = Instant::now();
+            sessions.retain(|_, s| s.expires > now);
+        }
+        if sessions.len() >= MAX_SESSIONS {
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, s)| s.expires)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                sessions.remove(&oldest);
+            }
+        }
+        sessions.insert(id.clone(), session);
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(id)?;
+        if session.expires <= Instant::now() {
+            sessions.remove(id);
+            return None;
+        }
+        Some(session.clone())
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random generator failed");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
@@ -0,0 +1,515 @@
+// Signed share links (--share-key). A link is a file or directory URL with
+// ?id=&exp=&sig= and optionally &max= (downloads) and &ip= (client address);
+// sig is an HMAC-SHA256 over the rest and the path, so links can be minted
+// offline (`lantrix share`) by anyone holding the key file. A valid link lets
+// its holder in without credentials, to that file or that directory and
+// everything below it. For directories the token is also handed back as a
+// cookie scoped to the directory, so the relative links of the listing keep
+// working. Revocations and download counts are kept in "<key file>.state".
+
+use std::{
+    collections::BTreeMap,
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+pub const COOKIE: &str = "lantrix_share";
+
+// Longest lifetime a link may be minted with.
+pub const MAX_TTL: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Token {
+    pub id: String,
+    pub path: String, // percent-encoded, below the root, "/" or "/a/b" (no trailing '/')
+    pub exp: u64,     // unix seconds
+    pub max: Option<u64>,
+    pub ip: Option<IpAddr>,
+    pub sig: String,
+}
+
+impl Token {
+    fn message(&self) -> String {
+        let max = self.max.map(|m| m.to_string()).unwrap_or_default();
+        let ip = self.ip.map(|ip| ip.to_string()).unwrap_or_default();
+        format!(
+            "lantrix-share\n{}\n{}\n{}\n{max}\n{ip}",
+            self.id, self.path, self.exp
+        )
+    }
+
+    // The link's own path is the request path, so it isn't repeated.
+    pub fn from_query(path: &str, query: &HashMap<String, String>) -> Option<Self> {
+        Some(Self {
+            id: query.get("id")?.clone(),
+            path: path.to_string(),
+            exp: query.get("exp")?.parse().ok()?,
+            max: match query.get("max") {
+                Some(m) => Some(m.parse().ok()?),
+                None => None,
+            },
+            ip: match query.get("ip") {
+                Some(ip) => Some(ip.parse().ok()?),
+                None => None,
+            },
+            sig: query.get("sig")?.clone(),
+        })
+    }
+
+    pub fn query(&self) -> String {
+        let mut q = format!("id={}&exp={}", self.id, self.exp);
+        if let Some(max) = self.max {
+            q.push_str(&format!("&max={max}"));
+        }
+        if let Some(ip) = self.ip {
+            q.push_str(&format!("&ip={}", urlencoding::encode(&ip.to_string())));
+        }
+        q.push_str(&format!("&sig={}", self.sig));
+        q
+    }
+
+    pub fn to_cookie(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    pub fn from_cookie(value: &str) -> Option<Self> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(value).ok()?).ok()
+    }
+
+    // Whether the token lets `path` (same form as `self.path`) in, assuming
+    // the token names a directory.
+    pub fn covers(&self, path: &str) -> bool {
+        self.path == "/"
+            || path == self.path
+            || path
+                .strip_prefix(self.path.as_str())
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    pub fn seconds_left(&self) -> u64 {
+        self.exp.saturating_sub(now())
+    }
+}
+
+// Persisted between restarts: revoked ids, and download counts of links with
+// a limit (dropped once the link has expired).
+#[derive(Default, Serialize, Deserialize)]
+struct Ledger {
+    revoked: Vec<String>,
+    downloads: BTreeMap<String, Downloads>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Downloads {
+    count: u64,
+    exp: u64,
+}
+
+pub struct Shares {
+    key: hmac::Key,
+    ledger: Mutex<Ledger>,
+    // Bumped with every ledger change, under the ledger lock.
+    generation: Mutex<u64>,
+    state: Arc<StateFile>,
+}
+
+// Where the ledger is written, and the generation last written there.
+// Writes happen off the async threads and outside the ledger lock; the
+// generation check keeps a slow, older write from replacing a newer one.
+struct StateFile {
+    path: PathBuf,
+    written: Mutex<u64>,
+}
+
+impl Shares {
+    // Reads the key (hex, 32 bytes), creating it on first use.
+    pub fn load(key_path: &Path) -> Result<Self, String> {
+        let key = match std::fs::read_to_string(key_path) {
+            Ok(text) => hex::decode(text.trim())
+                .ok()
+                .filter(|k| k.len() >= 32)
+                .ok_or_else(|| {
+                    format!("--share-key {}: expected 64 hex digits", key_path.display())
+                })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => create_key(key_path)?,
+            Err(e) => {
+                return Err(format!(
+                    "cannot read --share-key {}: {e}",
+                    key_path.display()
+                ))
+            }
+        };
+
+        let mut state_path = key_path.as_os_str().to_owned();
+        state_path.push(".state");
+        let state_path = PathBuf::from(state_path);
+        let ledger = match std::fs::read(&state_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| format!("{}: {e}", state_path.display()))?,
+            Err(_) => Ledger::default(),
+        };
+
+        Ok(Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &key),
+            ledger: Mutex::new(ledger),
+            generation: Mutex::new(0),
+            state: Arc::new(StateFile {
+                path: state_path,
+                written: Mutex::new(0),
+            }),
+        })
+    }
+
+    pub fn mint(&self, path: String, ttl: Duration, max: Option<u64>, ip: Option<IpAddr>) -> Token {
+        let mut id = [0u8; 8];
+        SystemRandom::new()
+            .fill(&mut id)
+            .expect("system random generator failed");
+        let mut token = Token {
+            id: hex::encode(id),
+            path,
+            exp: now() + ttl.min(MAX_TTL).as_secs(),
+            max,
+            ip,
+            sig: String::new(),
+        };
+        let tag = hmac::sign(&self.key, token.message().as_bytes());
+        token.sig = URL_SAFE_NO_PAD.encode(tag.as_ref());
+        token
+    }
+
+    // Signature, expiry, revocation, client address and download limit.
+    // With `reserve`, a download is counted in the same critical section, so
+    // concurrent requests can't get past the limit together; `release` gives
+    // it back if the response fails.
+    pub fn check(
+        &self,
+        token: &Token,
+        client: IpAddr,
+        reserve: bool,
+    ) -> Result<(), (StatusCode, &'static str)> {
+        let Ok(sig) = URL_SAFE_NO_PAD.decode(&token.sig) else {
+            return Err((StatusCode::FORBIDDEN, "Invalid share link"));
+        };
+        if hmac::verify(&self.key, token.message().as_bytes(), &sig).is_err() {
+            return Err((StatusCode::FORBIDDEN, "Invalid share link"));
+        }
+        if token.exp <= now() {
+            return Err((StatusCode::GONE, "Share link expired"));
+        }
+        if token.ip.is_some_and(|ip| ip != client) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Share link is bound to another address",
+            ));
+        }
+        let mut ledger = self.ledger.lock().unwrap();
+        if ledger.revoked.contains(&token.id) {
+            return Err((StatusCode::GONE, "Share link revoked"));
+        }
+        let Some(max) = token.max else {
+            return Ok(());
+        };
+        if ledger
+            .downloads
+            .get(&token.id)
+            .is_some_and(|d| d.count >= max)
+        {
+            return Err((StatusCode::GONE, "Share link download limit reached"));
+        }
+        if reserve {
+            let now = now();
+            ledger.downloads.retain(|_, d| d.exp > now);
+            ledger
+                .downloads
+                .entry(token.id.clone())
+                .or_insert(Downloads {
+                    count: 0,
+                    exp: token.exp,
+                })
+                .count += 1;
+            let snapshot = self.snapshot(&ledger);
+            drop(ledger);
+            self.save(snapshot);
+        }
+        Ok(())
+    }
+
+    // Undoes a download reserved by `check`.
+    pub fn release(&self, token: &Token) {
+        if token.max.is_none() {
+            return;
+        }
+        let mut ledger = self.ledger.lock().unwrap();
+        let Some(d) = ledger.downloads.get_mut(&token.id) else {
+            return;
+        };
+        d.count = d.count.saturating_sub(1);
+        let snapshot = self.snapshot(&ledger);
+        drop(ledger);
+        self.save(snapshot);
+    }
+
+    // False when the id was already revoked.
+    pub fn revoke(&self, id: &str) -> bool {
+        let mut ledger = self.ledger.lock().unwrap();
+        if ledger.revoked.iter().any(|r| r == id) {
+            return false;
+        }
+        ledger.revoked.push(id.to_string());
+        ledger.downloads.remove(id);
+        let snapshot = self.snapshot(&ledger);
+        drop(ledger);
+        self.save(snapshot);
+        true
+    }
+
+    // Serialized while the caller still holds the ledger lock, so snapshots
+    // are numbered in the order the changes happened.
+    fn snapshot(&self, ledger: &Ledger) -> (u64, Vec<u8>) {
+        let mut generation = self.generation.lock().unwrap();
+        *generation += 1;
+        (
+            *generation,
+            serde_json::to_vec_pretty(ledger).unwrap_or_default(),
+        )
+    }
+
+    // Written on the blocking pool, to a temporary file that is then
+    // renamed, so a crash can't leave half a ledger behind.
+    fn save(&self, (generation, json): (u64, Vec<u8>)) {
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || state.write(generation, &json));
+    }
+}
+
+impl StateFile {
+    fn write(&self, generation: u64, json: &[u8]) {
+        let mut written = self.written.lock().unwrap();
+        if *written >= generation {
+            return;
+        }
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let result = std::fs::write(&tmp, json).and_then(|_| std::fs::rename(&tmp, &self.path));
+        match result {
+            Ok(()) => *written = generation,
+            Err(e) => eprintln!("cannot write {}: {e}", self.path.display()),
+        }
+    }
+}
+
+fn create_key(path: &Path) -> Result<Vec<u8>, String> {
+    let mut key = vec![0u8; 32];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| "system random generator failed".to_string())?;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let write = options.open(path).and_then(|mut f| {
+        std::io::Write::write_all(&mut f, format!("{}\n", hex::encode(&key)).as_bytes())
+    });
+    write.map_err(|e| format!("cannot create --share-key {}: {e}", path.display()))?;
+    eprintln!("Created share key {}", path.display());
+    Ok(key)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn new_shares(name: &str) -> (Shares, PathBuf) {
+        let key = std::env::temp_dir().join(format!("lantrix-{}-{name}.key", std::process::id()));
+        let _ = std::fs::remove_file(&key);
+        let _ = std::fs::remove_file(key.with_extension("key.state"));
+        (Shares::load(&key).unwrap(), key)
+    }
+
+    fn cleanup(key: &Path) {
+        let _ = std::fs::remove_file(key);
+        let _ = std::fs::remove_file(key.with_extension("key.state"));
+    }
+
+    fn status(result: Result<(), (StatusCode, &'static str)>) -> Option<StatusCode> {
+        result.err().map(|(status, _)| status)
+    }
+
+    #[test]
+    fn covers_whole_segments() {
+        let token = |path: &str| Token {
+            id: String::new(),
+            path: path.to_string(),
+            exp: 0,
+            max: None,
+            ip: None,
+            sig: String::new(),
+        };
+        assert!(token("/a").covers("/a"));
+        assert!(token("/a").covers("/a/b/c"));
+        assert!(!token("/a").covers("/ab"));
+        assert!(!token("/a").covers("/"));
+        assert!(!token("/a/b").covers("/a"));
+        assert!(token("/").covers("/anything"));
+    }
+
+    #[tokio::test]
+    async fn signature_covers_every_field() {
+        let (shares, key) = new_shares("sig");
+        let ip = Some(CLIENT);
+        let token = shares.mint("/a%20b".to_string(), DAY, Some(3), ip);
+        assert_eq!(status(shares.check(&token, CLIENT, false)), None);
+
+        let tampered: [fn(&mut Token); 6] = [
+            |t| t.path = "/other".to_string(),
+            |t| t.id = "0000000000000000".to_string(),
+            |t| t.exp += 1,
+            |t| t.max = None,
+            |t| t.ip = None,
+            |t| t.sig = "AAAA".to_string(),
+        ];
+        for tamper in tampered {
+            let mut t = token.clone();
+            tamper(&mut t);
+            assert_eq!(
+                status(shares.check(&t, CLIENT, false)),
+                Some(StatusCode::FORBIDDEN)
+            );
+        }
+
+        // another key doesn't accept it
+        let (other, other_key) = new_shares("sig-other");
+        assert_eq!(
+            status(other.check(&token, CLIENT, false)),
+            Some(StatusCode::FORBIDDEN)
+        );
+        cleanup(&key);
+        cleanup(&other_key);
+    }
+
+    #[tokio::test]
+    async fn expiry_and_ip_binding() {
+        let (shares, key) = new_shares("expiry");
+        let expired = shares.mint("/f".to_string(), Duration::ZERO, None, None);
+        assert_eq!(
+            status(shares.check(&expired, CLIENT, false)),
+            Some(StatusCode::GONE)
+        );
+
+        let long = shares.mint("/f".to_string(), MAX_TTL * 2, None, None);
+        assert!(long.seconds_left() <= MAX_TTL.as_secs());
+
+        let bound = shares.mint("/f".to_string(), DAY, None, Some(CLIENT));
+        let other = IpAddr::from([192, 0, 2, 2]);
+        assert_eq!(status(shares.check(&bound, CLIENT, false)), None);
+        assert_eq!(
+            status(shares.check(&bound, other, false)),
+            Some(StatusCode::FORBIDDEN)
+        );
+        cleanup(&key);
+    }
+
+    #[tokio::test]
+    async fn download_limit_is_reserved() {
+        let (shares, key) = new_shares("limit");
+        let token = shares.mint("/f".to_string(), DAY, Some(2), None);
+        // checking without reserving doesn't use anything up
+        for _ in 0..5 {
+            assert_eq!(status(shares.check(&token, CLIENT, false)), None);
+        }
+        assert_eq!(status(shares.check(&token, CLIENT, true)), None);
+        assert_eq!(status(shares.check(&token, CLIENT, true)), None);
+        assert_eq!(
+            status(shares.check(&token, CLIENT, true)),
+            Some(StatusCode::GONE)
+        );
+        shares.release(&token);
+        assert_eq!(status(shares.check(&token, CLIENT, true)), None);
+        assert_eq!(
+            status(shares.check(&token, CLIENT, false)),
+            Some(StatusCode::GONE)
+        );
+
+        // links without a limit are never counted
+        let unlimited = shares.mint("/f".to_string(), DAY, None, None);
+        for _ in 0..5 {
+            assert_eq!(status(shares.check(&unlimited, CLIENT, true)), None);
+        }
+        cleanup(&key);
+    }
+
+    #[tokio::test]
+    async fn revocations_persist() {
+        let (shares, key) = new_shares("revoke");
+        let token = shares.mint("/f".to_string(), DAY, None, None);
+        assert!(shares.revoke(&token.id));
+        assert!(!shares.revoke(&token.id));
+        assert_eq!(
+            status(shares.check(&token, CLIENT, false)),
+            Some(StatusCode::GONE)
+        );
+
+        // the ledger is written in the background
+        let state = key.with_extension("key.state");
+        for _ in 0..100 {
+            if std::fs::read_to_string(&state).is_ok_and(|s| s.contains(&token.id)) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let reloaded = Shares::load(&key).unwrap();
+        assert_eq!(
+            status(reloaded.check(&token, CLIENT, false)),
+            Some(StatusCode::GONE)
+        );
+        cleanup(&key);
+    }
+
+    #[test]
+    fn query_and_cookie_round_trip() {
+        let token = Token {
+            id: "0123456789abcdef".to_string(),
+            path: "/d".to_string(),
+            exp: 1_700_000_000,
+            max: Some(5),
+            ip: Some("2001:db8::1".parse().unwrap()),
+            sig: "c2ln".to_string(),
+        };
+        let query: HashMap<String, String> = token
+            .query()
+            .split('&')
+            .filter_map(|kv| kv.split_once('='))
+            .map(|(k, v)| (k.to_string(), urlencoding::decode(v).unwrap().into_owned()))
+            .collect();
+        let parsed = Token::from_query("/d", &query).unwrap();
+        assert_eq!(parsed.message(), token.message());
+        assert_eq!(parsed.sig, token.sig);
+
+        let cookie = Token::from_cookie(&token.to_cookie()).unwrap();
+        assert_eq!(cookie.message(), token.message());
+        assert!(Token::from_cookie("not a cookie").is_none());
+    }
+}
@@ -0,0 +1,458 @@
+// Listing templates (minijinja). The built-in theme is compiled in;
+// --template-dir points at a directory whose files take precedence, so a
+// deployment can override just theme.css or replace listing.html entirely.
+
+use std::path::{Path, PathBuf};
+
+use minijinja::Environment;
+use serde::Serialize;
+
+use crate::{
+    gallery,
+    listing::{self, Entry, Sort, SortKey, Walked},
+    search,
+};
+
+pub const LISTING_TEMPLATE: &str = "listing.html";
+pub const MARKDOWN_TEMPLATE: &str = "markdown.html";
+pub const GALLERY_TEMPLATE: &str = "gallery.html";
+pub const LOGIN_TEMPLATE: &str = "login.html";
+
+const BUILTIN: &[(&str, &str)] = &[
+    (
+        LISTING_TEMPLATE,
+        include_str!("../assets/templates/listing.html"),
+    ),
+    (
+        MARKDOWN_TEMPLATE,
+        include_str!("../assets/templates/markdown.html"),
+    ),
+    (
+        GALLERY_TEMPLATE,
+        include_str!("../assets/templates/gallery.html"),
+    ),
+    (
+        LOGIN_TEMPLATE,
+        include_str!("../assets/templates/login.html"),
+    ),
+    ("theme.css", include_str!("../assets/templates/theme.css")),
+];
+
+pub struct Theme {
+    env: Environment<'static>,
+}
+
+impl Theme {
+    pub fn load(dir: Option<&Path>) -> Result<Self, String> {
+        let mut env = Environment::new();
+        let disk = dir.map(minijinja::path_loader);
+        env.set_loader(move |name| {
+            if let Some(disk) = &disk {
+                if let Some(source) = disk(name)? {
+                    return Ok(Some(source));
+                }
+            }
+            Ok(BUILTIN
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, source)| source.to_string()))
+        });
+
+        // Surface syntax errors at startup rather than on the first request.
+        for name in [
+            LISTING_TEMPLATE,
+            MARKDOWN_TEMPLATE,
+            GALLERY_TEMPLATE,
+            LOGIN_TEMPLATE,
+        ] {
+            env.get_template(name)
+                .map_err(|e| format!("cannot load {name}: {e:#}"))?;
+        }
+        Ok(Self { env })
+    }
+
+    pub fn render_listing(&self, ctx: &ListingContext) -> Result<String, minijinja::Error> {
+        self.env.get_template(LISTING_TEMPLATE)?.render(ctx)
+    }
+
+    pub fn render_markdown(&self, ctx: &MarkdownContext) -> Result<String, minijinja::Error> {
+        self.env.get_template(MARKDOWN_TEMPLATE)?.render(ctx)
+    }
+
+    pub fn render_gallery(&self, ctx: &GalleryContext) -> Result<String, minijinja::Error> {
+        self.env.get_template(GALLERY_TEMPLATE)?.render(ctx)
+    }
+
+    pub fn render_login(&self, ctx: &LoginContext) -> Result<String, minijinja::Error> {
+        self.env.get_template(LOGIN_TEMPLATE)?.render(ctx)
+    }
+}
+
+// Everything a listing template can use. URLs are already percent-encoded
+// and absolute ones include the --route-prefix; names are raw and escaped by
+// the template engine.
+#[derive(Serialize)]
+pub struct ListingContext {
+    pub path: String,
+    pub breadcrumbs: Vec<Link>,
+    pub parent: Option<String>,
+    pub entries: Vec<TemplateEntry>,
+    pub sort: SortInfo,
+    pub columns: Vec<Column>,
+    pub archives: Vec<Link>,
+    pub console: Option<String>,
+    pub gallery: Option<String>,
+    pub readme: Option<Readme>,
+    pub search: Option<SearchInfo>,
+    pub pagination: Option<Pagination>,
+    pub checksum: Option<ChecksumColumn>,
+    pub account: Option<Link>, // signed-in user and the sign-out page, with --login-form
+    pub server: ServerInfo,
+}
+
+// The digest column shown with --checksums. Entries carry the hex digest
+// when it is already cached; otherwise the template links to ?checksum=alg.
+#[derive(Serialize)]
+pub struct ChecksumColumn {
+    pub alg: &'static str,
+    pub label: &'static str,
+}
+
+// Page navigation, set when a listing spans more than one page. Links are
+// None on the first/last page respectively.
+#[derive(Serialize)]
+pub struct Pagination {
+    pub page: usize,
+    pub pages: usize,
+    pub total: usize,
+    pub first: Option<String>,
+    pub prev: Option<String>,
+    pub next: Option<String>,
+    pub last: Option<String>,
+}
+
+// Set when the listing shows ?q= results instead of the directory itself.
+#[derive(Serialize)]
+pub struct SearchInfo {
+    pub query: String,
+    pub mode: &'static str,
+    pub truncated: bool,
+    pub timed_out: bool,
+}
+
+// A README rendered below the listing; `html` is already sanitized.
+#[derive(Serialize)]
+pub struct Readme {
+    pub name: String,
+    pub html: String,
+}
+
+// The ?view=gallery page: subdirectories plus a thumbnail grid of the images.
+#[derive(Serialize)]
+pub struct GalleryContext {
+    pub path: String,
+    pub breadcrumbs: Vec<Link>,
+    pub parent: Option<String>,
+    pub dirs: Vec<TemplateEntry>,
+    pub images: Vec<TemplateEntry>,
+    pub thumb_size: u32,
+    pub server: ServerInfo,
+}
+
+impl GalleryContext {
+    pub fn new(prefix: &str, root: &Path, dir: &Path, entries: &[Entry], thumb_size: u32) -> Self {
+        let is_image = |e: &Entry| e.mime().is_some_and(|m| gallery::is_image(&m));
+        Self {
+            path: crate::display_rel(root, dir),
+            breadcrumbs: breadcrumbs(prefix, root, dir, true),
+            parent: (dir != root).then(|| "../".to_string()),
+            dirs: entries
+                .iter()
+                .filter(|e| e.is_dir)
+                .map(TemplateEntry::new)
+                .collect(),
+            images: entries
+                .iter()
+                .filter(|e| is_image(e))
+                .map(TemplateEntry::new)
+                .collect(),
+            thumb_size,
+            server: ServerInfo::current(),
+        }
+    }
+}
+
+// The ?render=1 view of a markdown file.
+#[derive(Serialize)]
+pub struct MarkdownContext {
+    pub path: String,
+    pub name: String,
+    pub breadcrumbs: Vec<Link>,
+    pub html: String,
+    pub raw_url: String,
+    pub server: ServerInfo,
+}
+
+impl MarkdownContext {
+    pub fn new(prefix: &str, root: &Path, file: &Path, html: String) -> Self {
+        let file_name = file.file_name().unwrap_or_default();
+        Self {
+            path: crate::display_rel(root, file),
+            raw_url: format!("{}?render=0", listing::encode_segment(file_name)),
+            name: file_name.to_string_lossy().into_owned(),
+            breadcrumbs: breadcrumbs(prefix, root, file, false),
+            html,
+            server: ServerInfo::current(),
+        }
+    }
+}
+
+// The --login-form page: the sign-in form, or for a signed-in user the
+// sign-out button (which posts the session's CSRF token).
+#[derive(Serialize)]
+pub struct LoginContext {
+    pub action: String,
+    pub next: String,
+    pub name: String, // user name to prefill after a failed attempt
+    pub error: Option<String>,
+    pub user: Option<String>,
+    pub logout: String,
+    pub csrf: String,
+    pub home: String,
+    pub server: ServerInfo,
+}
+
+impl LoginContext {
+    pub fn new(prefix: &str, next: &str) -> Self {
+        Self {
+            action: format!("{prefix}/__login"),
+            next: next.to_string(),
+            name: String::new(),
+            error: None,
+            user: None,
+            logout: format!("{prefix}/__logout"),
+            csrf: String::new(),
+            home: format!("{prefix}/"),
+            server: ServerInfo::current(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Link {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Serialize)]
+pub struct TemplateEntry {
+    pub name: String,
+    pub url: String,
+    pub is_dir: bool,
+    pub icon: &'static str,
+    pub size: u64,
+    pub size_human: String,
+    pub mtime: String,
+    pub mtime_iso: Option<String>,
+    pub mime: Option<String>,
+    pub checksum: Option<String>,
+}
+
+impl TemplateEntry {
+    pub fn new(e: &Entry) -> Self {
+        let mut url = e.href();
+        if e.is_dir {
+            url.push('/');
+        }
+        Self {
+            name: e.name.clone(),
+            url,
+            is_dir: e.is_dir,
+            icon: e.icon(),
+            size: e.size,
+            size_human: listing::human_size(e.size),
+            mtime: e.mtime.map(listing::format_mtime).unwrap_or_default(),
+            mtime_iso: e.mtime.map(listing::format_rfc3339),
+            mime: e.mime(),
+            checksum: None,
+        }
+    }
+
+    // A search hit, shown and linked by its path below the searched directory.
+    pub fn found(w: &Walked) -> Self {
+        let mut entry = Self::new(&w.entry);
+        entry.name = w.rel.clone();
+        entry.url = w.href.clone();
+        if w.entry.is_dir {
+            entry.url.push('/');
+        }
+        entry
+    }
+}
+
+#[derive(Serialize)]
+pub struct SortInfo {
+    pub key: &'static str,
+    pub order: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct Column {
+    pub key: &'static str,
+    pub label: &'static str,
+    pub url: String,
+    pub active: bool,
+    pub numeric: bool,
+}
+
+#[derive(Serialize)]
+pub struct ServerInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+impl ServerInfo {
+    fn current() -> Self {
+        Self {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+}
+
+impl ListingContext {
+    pub fn new(
+        prefix: &str,
+        root: &Path,
+        dir: &Path,
+        entries: &[Entry],
+        sort: Sort,
+        console: bool,
+    ) -> Self {
+        let columns = [
+            (SortKey::Name, "Name", false),
+            (SortKey::Size, "Size", true),
+            (SortKey::Mtime, "Modified", false),
+            (SortKey::Type, "Type", false),
+        ]
+        .into_iter()
+        .map(|(key, label, numeric)| {
+            // Clicking the active column flips the order; others start ascending.
+            let desc = sort.key == key && !sort.desc;
+            Column {
+                key: key.as_str(),
+                label,
+                url: format!(
+                    "?sort={}&order={}",
+                    key.as_str(),
+                    if desc { "desc" } else { "asc" }
+                ),
+                active: sort.key == key,
+                numeric,
+            }
+        })
+        .collect();
+
+        let archives = [crate::archive::Format::Zip, crate::archive::Format::TarGz]
+            .into_iter()
+            .map(|f| Link {
+                name: format!(".{}", f.extension()),
+                url: format!("?archive={}", f.extension()),
+            })
+            .collect();
+
+        Self {
+            path: crate::display_rel(root, dir),
+            breadcrumbs: breadcrumbs(prefix, root, dir, true),
+            parent: (dir != root).then(|| "../".to_string()),
+            entries: entries.iter().map(TemplateEntry::new).collect(),
+            sort: SortInfo {
+                key: sort.key.as_str(),
+                order: if sort.desc { "desc" } else { "asc" },
+            },
+            columns,
+            archives,
+            console: console.then(|| format!("{prefix}/__console")),
+            gallery: entries
+                .iter()
+                .any(|e| e.mime().is_some_and(|m| gallery::is_image(&m)))
+                .then(|| "?view=gallery".to_string()),
+            readme: None,
+            search: None,
+            pagination: None,
+            checksum: None,
+            account: None,
+            server: ServerInfo::current(),
+        }
+    }
+
+    // Adds page links for page `page` of `total` entries, `limit` per page.
+    // A non-default page size is carried over into the sort links.
+    pub fn paginate(&mut self, sort: Sort, page: usize, limit: usize, total: usize) {
+        let limit_param = if limit == listing::DEFAULT_PAGE_SIZE {
+            String::new()
+        } else {
+            format!("&limit={limit}")
+        };
+        for column in &mut self.columns {
+            column.url.push_str(&limit_param);
+        }
+
+        let pages = total.div_ceil(limit).max(1);
+        if pages == 1 {
+            return;
+        }
+        let link = |n: usize| {
+            format!(
+                "?sort={}&order={}&page={n}{limit_param}",
+                sort.key.as_str(),
+                if sort.desc { "desc" } else { "asc" }
+            )
+        };
+        self.pagination = Some(Pagination {
+            page,
+            pages,
+            total,
+            first: (page > 1).then(|| link(1)),
+            prev: (page > 1).then(|| link(page - 1)),
+            next: (page < pages).then(|| link(page + 1)),
+            last: (page < pages).then(|| link(pages)),
+        });
+    }
+
+    // Turns the context into a results page for ?q=; sort links keep the query.
+    pub fn show_search(&mut self, query: &str, mode: search::Mode, results: &search::Results) {
+        let carry = format!("&q={}&match={}", urlencoding::encode(query), mode.as_str());
+        for column in &mut self.columns {
+            column.url.push_str(&carry);
+        }
+        self.entries = results.hits.iter().map(TemplateEntry::found).collect();
+        self.gallery = None;
+        self.search = Some(SearchInfo {
+            query: query.to_string(),
+            mode: mode.as_str(),
+            truncated: results.truncated,
+            timed_out: results.timed_out,
+        });
+    }
+}
+
+// "/", "a", "b" for /a/b, each linking to its location; `is_dir` says
+// whether the last one is a directory.
+fn breadcrumbs(prefix: &str, root: &Path, path: &Path, is_dir: bool) -> Vec<Link> {
+    let mut crumbs = vec![Link {
+        name: "/".to_string(),
+        url: format!("{prefix}/"),
+    }];
+    let rel = path.strip_prefix(root).unwrap_or(Path::new(""));
+    let count = rel.iter().count();
+    let mut current = PathBuf::from(root);
+    for (i, seg) in rel.iter().enumerate() {
+        current.push(seg);
+        crumbs.push(Link {
+            name: seg.to_string_lossy().into_owned(),
+            url: listing::url_for(prefix, root, &current, is_dir || i + 1 < count),
+        });
+    }
+    crumbs
+}